@@ -0,0 +1,392 @@
+//! The authentication of the admins: hashed passwords given to the search
+//! server, API tokens stored in the database and the CSRF tokens of the forms.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::net::IpAddr;
+use std::num::NonZeroU32;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use base64::engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD};
+use base64::Engine;
+use ring::rand::{SecureRandom, SystemRandom};
+use ring::{digest, hmac, pbkdf2};
+use serde::{Deserialize, Serialize};
+
+use crate::progress::unix_timestamp;
+
+const PBKDF2_ITERATIONS: u32 = 100_000;
+const CSRF_TOKEN_LIFETIME: Duration = Duration::from_secs(24 * 60 * 60);
+const SESSION_LIFETIME: Duration = Duration::from_secs(12 * 60 * 60);
+
+/// A well-formed hash of no password, checked against when the admin is unknown.
+const DUMMY_HASH: &str =
+    "pbkdf2-sha256$100000$AAAAAAAAAAAAAAAAAAAAAA==$AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=";
+
+/// Hashes a password into a `pbkdf2-sha256${iterations}${salt}${hash}` string.
+pub fn hash_password(password: &str) -> String {
+    let mut salt = [0; 16];
+    SystemRandom::new().fill(&mut salt).expect("no system randomness available");
+    let mut hash = [0; digest::SHA256_OUTPUT_LEN];
+    let iterations = NonZeroU32::new(PBKDF2_ITERATIONS).unwrap();
+    pbkdf2::derive(pbkdf2::PBKDF2_HMAC_SHA256, iterations, &salt, password.as_bytes(), &mut hash);
+    let (salt, hash) = (STANDARD.encode(salt), STANDARD.encode(hash));
+    format!("pbkdf2-sha256${iterations}${salt}${hash}")
+}
+
+/// Checks a password against a hash made by [`hash_password`], in constant time.
+pub fn verify_password(password: &str, hashed: &str) -> bool {
+    let mut parts = hashed.split('$');
+    let (Some("pbkdf2-sha256"), Some(iterations), Some(salt), Some(hash), None) =
+        (parts.next(), parts.next(), parts.next(), parts.next(), parts.next())
+    else {
+        return false;
+    };
+    let Ok(iterations) = iterations.parse() else { return false };
+    let (Ok(salt), Ok(hash)) = (STANDARD.decode(salt), STANDARD.decode(hash)) else {
+        return false;
+    };
+    let algorithm = pbkdf2::PBKDF2_HMAC_SHA256;
+    pbkdf2::verify(algorithm, iterations, &salt, password.as_bytes(), &hash).is_ok()
+}
+
+/// The admins allowed to use the admin pages, by name, with their hashed password.
+#[derive(Debug, Default, Clone)]
+pub struct Credentials {
+    hashes: HashMap<String, String>,
+}
+
+impl Credentials {
+    /// Parses `{name}:{hash}` lines, empty lines and lines starting with `#` are ignored.
+    pub fn parse(text: &str) -> Result<Credentials, InvalidCredentials> {
+        let mut hashes = HashMap::new();
+        for (number, line) in (1..).zip(text.lines()) {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            match line.split_once(':') {
+                Some((name, hash)) if !name.is_empty() && hash.starts_with("pbkdf2-sha256$") => {
+                    hashes.insert(name.to_owned(), hash.to_owned());
+                }
+                _ => return Err(InvalidCredentials { line: number }),
+            }
+        }
+        Ok(Credentials { hashes })
+    }
+
+    pub fn extend(&mut self, other: Credentials) {
+        self.hashes.extend(other.hashes);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.hashes.is_empty()
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.hashes.contains_key(name)
+    }
+
+    /// Checks the password of an admin, the unknown names take as long to check
+    /// as the known ones so that the response time doesn't tell which admins exist.
+    pub fn verify(&self, name: &str, password: &str) -> bool {
+        match self.hashes.get(name) {
+            Some(hash) => verify_password(password, hash),
+            None => {
+                verify_password(password, DUMMY_HASH);
+                false
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct InvalidCredentials {
+    line: usize,
+}
+
+impl fmt::Display for InvalidCredentials {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {} is not a `name:pbkdf2-sha256$...` credential", self.line)
+    }
+}
+
+impl std::error::Error for InvalidCredentials {}
+
+/// An API token, only the digest of the token is stored.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct ApiToken {
+    pub name: String,
+    /// When the token was created, in seconds since the Unix epoch.
+    pub created_at: u64,
+}
+
+/// Returns a new random API token, it is only shown once.
+pub fn generate_api_token() -> String {
+    URL_SAFE_NO_PAD.encode(generate_secret())
+}
+
+/// The digest under which an API token is stored.
+pub fn api_token_digest(token: &str) -> String {
+    URL_SAFE_NO_PAD.encode(digest::digest(&digest::SHA256, token.as_bytes()))
+}
+
+/// Signs the CSRF tokens of the admin forms, the tokens are no longer
+/// valid when the server restarts as the key is generated at startup.
+pub struct CsrfKey {
+    key: hmac::Key,
+}
+
+impl CsrfKey {
+    pub fn generate() -> CsrfKey {
+        let key = hmac::Key::generate(hmac::HMAC_SHA256, &SystemRandom::new())
+            .expect("no system randomness available");
+        CsrfKey { key }
+    }
+
+    /// Returns a `{timestamp}.{signature}` token valid for a day, only for this admin.
+    pub fn token(&self, admin: &str) -> String {
+        let timestamp = unix_timestamp().to_string();
+        let signature = hmac::sign(&self.key, csrf_message(&timestamp, admin).as_bytes());
+        format!("{timestamp}.{}", URL_SAFE_NO_PAD.encode(signature))
+    }
+
+    pub fn verify(&self, admin: &str, token: &str) -> bool {
+        let Some((timestamp, signature)) = token.split_once('.') else { return false };
+        let Ok(signature) = URL_SAFE_NO_PAD.decode(signature) else { return false };
+        let Ok(issued_at) = timestamp.parse::<u64>() else { return false };
+        hmac::verify(&self.key, csrf_message(timestamp, admin).as_bytes(), &signature).is_ok()
+            && unix_timestamp().saturating_sub(issued_at) <= CSRF_TOKEN_LIFETIME.as_secs()
+    }
+}
+
+/// The name comes last, the timestamp before it cannot contain the separator.
+fn csrf_message(timestamp: &str, admin: &str) -> String {
+    format!("{timestamp}\n{admin}")
+}
+
+/// Signs the session cookies of the admins logged in with a password, their password
+/// is only hashed once per session. The sessions end when the server restarts.
+pub struct SessionKey {
+    key: hmac::Key,
+}
+
+impl SessionKey {
+    pub fn generate() -> SessionKey {
+        let key = hmac::Key::generate(hmac::HMAC_SHA256, &SystemRandom::new())
+            .expect("no system randomness available");
+        SessionKey { key }
+    }
+
+    /// Returns a `{timestamp}.{name}.{signature}` session valid for twelve hours,
+    /// the name is encoded to be allowed in a cookie.
+    pub fn session(&self, name: &str) -> String {
+        let payload = format!("{}.{}", unix_timestamp(), URL_SAFE_NO_PAD.encode(name));
+        let signature = hmac::sign(&self.key, payload.as_bytes());
+        format!("{payload}.{}", URL_SAFE_NO_PAD.encode(signature))
+    }
+
+    /// Returns the name of the admin of a valid session.
+    pub fn verify(&self, session: &str) -> Option<String> {
+        let (payload, signature) = session.rsplit_once('.')?;
+        let (timestamp, name) = payload.split_once('.')?;
+        let signature = URL_SAFE_NO_PAD.decode(signature).ok()?;
+        hmac::verify(&self.key, payload.as_bytes(), &signature).ok()?;
+        let issued_at = timestamp.parse::<u64>().ok()?;
+        if unix_timestamp().saturating_sub(issued_at) > SESSION_LIFETIME.as_secs() {
+            return None;
+        }
+        String::from_utf8(URL_SAFE_NO_PAD.decode(name).ok()?).ok()
+    }
+}
+
+/// Counts the failed logins of the clients to slow down the guessing
+/// of passwords, a client is blocked for a while after a few failures.
+#[derive(Default)]
+pub struct FailedLogins {
+    /// The number of failures of the clients and when the last one happened.
+    failures: Mutex<HashMap<IpAddr, (u32, Instant)>>,
+}
+
+impl FailedLogins {
+    pub const MAX_FAILURES: u32 = 5;
+    pub const BLOCKED_FOR: Duration = Duration::from_secs(60);
+
+    /// Whether the client failed too many times, we must not check its password.
+    pub fn is_blocked(&self, client: IpAddr) -> bool {
+        let failures = self.failures.lock().unwrap();
+        failures.get(&client).is_some_and(|(count, last)| {
+            *count >= Self::MAX_FAILURES && last.elapsed() < Self::BLOCKED_FOR
+        })
+    }
+
+    pub fn failed(&self, client: IpAddr) {
+        let mut failures = self.failures.lock().unwrap();
+        // The clients that stopped failing are forgotten
+        failures.retain(|_, (_, last)| last.elapsed() < Self::BLOCKED_FOR);
+        let (count, last) = failures.entry(client).or_insert((0, Instant::now()));
+        *count += 1;
+        *last = Instant::now();
+    }
+
+    pub fn succeeded(&self, client: IpAddr) {
+        self.failures.lock().unwrap().remove(&client);
+    }
+}
+
+/// Returns random bytes to use as a secret key.
+pub fn generate_secret() -> [u8; 32] {
+    let mut secret = [0; 32];
+    SystemRandom::new().fill(&mut secret).expect("no system randomness available");
+    secret
+}
+
+/// Signs the URLs of the results so that the redirect route only
+/// follows the links we generated and cannot be used by anyone else.
+pub struct UrlSigner {
+    key: hmac::Key,
+}
+
+impl UrlSigner {
+    pub fn new(secret: &[u8]) -> UrlSigner {
+        UrlSigner { key: hmac::Key::new(hmac::HMAC_SHA256, secret) }
+    }
+
+    fn sign(&self, message: &str) -> String {
+        URL_SAFE_NO_PAD.encode(hmac::sign(&self.key, message.as_bytes()))
+    }
+
+    fn verify(&self, message: &str, signature: &str) -> bool {
+        let Ok(signature) = URL_SAFE_NO_PAD.decode(signature) else { return false };
+        hmac::verify(&self.key, message.as_bytes(), &signature).is_ok()
+    }
+
+    /// Signs the link of a result: the document, its URL and the search and position
+    /// of the result when the search was logged, so that the clicks cannot be forged.
+    pub fn sign_link(&self, docid: u64, url: &str, click: Option<(u64, u32)>) -> String {
+        self.sign(&link_message(docid, url, click))
+    }
+
+    pub fn verify_link(
+        &self,
+        docid: u64,
+        url: &str,
+        click: Option<(u64, u32)>,
+        signature: &str,
+    ) -> bool {
+        self.verify(&link_message(docid, url, click), signature)
+    }
+}
+
+/// The URL comes last, the numbers before it cannot contain the separator.
+fn link_message(docid: u64, url: &str, click: Option<(u64, u32)>) -> String {
+    match click {
+        Some((search, position)) => format!("{docid}\n{search}\n{position}\n{url}"),
+        None => format!("{docid}\n\n\n{url}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn passwords_and_credentials() {
+        let hash = hash_password("croissant");
+        assert!(hash.starts_with("pbkdf2-sha256$100000$"));
+        assert!(verify_password("croissant", &hash));
+        assert!(!verify_password("baguette", &hash));
+        assert!(!verify_password("croissant", "pbkdf2-sha256$100000$salt"));
+
+        let text = format!("# The admins\n\nkero:{hash}\n");
+        let credentials = Credentials::parse(&text).unwrap();
+        assert!(credentials.verify("kero", "croissant"));
+        assert!(!credentials.verify("kero", "baguette"));
+        assert!(!credentials.verify("admin", "croissant"));
+
+        // The unknown admins are checked against a hash as costly as the real ones
+        let (iterations, salt_and_hash) =
+            DUMMY_HASH.split_once('$').unwrap().1.split_once('$').unwrap();
+        assert_eq!(iterations, PBKDF2_ITERATIONS.to_string());
+        let (salt, hash) = salt_and_hash.split_once('$').unwrap();
+        assert_eq!(STANDARD.decode(salt).unwrap().len(), 16);
+        assert_eq!(STANDARD.decode(hash).unwrap().len(), digest::SHA256_OUTPUT_LEN);
+
+        let error = Credentials::parse("kero:croissant").unwrap_err();
+        assert_eq!(error.to_string(), "line 1 is not a `name:pbkdf2-sha256$...` credential");
+    }
+
+    #[test]
+    fn signed_urls() {
+        let signer = UrlSigner::new(&generate_secret());
+        let url = "https://www.service-public.fr/";
+        let signature = signer.sign_link(3, url, None);
+        assert!(signer.verify_link(3, url, None, &signature));
+        assert!(!signer.verify_link(3, "https://evil.example/", None, &signature));
+        assert!(!signer.verify_link(4, url, None, &signature));
+        assert!(!signer.verify_link(3, url, Some((12, 0)), &signature));
+        assert!(!signer.verify_link(3, url, None, "croissant"));
+        assert!(!UrlSigner::new(&generate_secret()).verify_link(3, url, None, &signature));
+
+        // The document, the search and the position of a result cannot be changed
+        let signature = signer.sign_link(3, url, Some((12, 0)));
+        assert!(signer.verify_link(3, url, Some((12, 0)), &signature));
+        assert!(!signer.verify_link(4, url, Some((12, 0)), &signature));
+        assert!(!signer.verify_link(3, url, Some((13, 0)), &signature));
+        assert!(!signer.verify_link(3, url, Some((12, 1)), &signature));
+        assert!(!signer.verify_link(3, url, None, &signature));
+    }
+
+    #[test]
+    fn csrf_tokens() {
+        let key = CsrfKey::generate();
+        let token = key.token("kero");
+        assert!(key.verify("kero", &token));
+        assert!(!CsrfKey::generate().verify("kero", &token));
+        assert!(!key.verify("kero", ""));
+
+        // A token issued for an admin is not valid for another one
+        assert!(!key.verify("loic", &token));
+        assert!(key.verify("loic", &key.token("loic")));
+
+        // A token issued two days ago has expired
+        let timestamp = (unix_timestamp() - 2 * 24 * 60 * 60).to_string();
+        let signature = hmac::sign(&key.key, csrf_message(&timestamp, "kero").as_bytes());
+        let token = format!("{timestamp}.{}", URL_SAFE_NO_PAD.encode(signature));
+        assert!(!key.verify("kero", &token));
+    }
+
+    #[test]
+    fn admin_sessions() {
+        let key = SessionKey::generate();
+        let session = key.session("kero.admin");
+        assert_eq!(key.verify(&session).as_deref(), Some("kero.admin"));
+        assert_eq!(SessionKey::generate().verify(&session), None);
+        let name = URL_SAFE_NO_PAD.encode("kero.admin");
+        let forged = session.replace(&name, &URL_SAFE_NO_PAD.encode("root"));
+        assert_eq!(key.verify(&forged), None);
+        assert_eq!(key.verify(""), None);
+
+        // A session opened a day ago has expired
+        let payload =
+            format!("{}.{}", unix_timestamp() - 24 * 60 * 60, URL_SAFE_NO_PAD.encode("kero"));
+        let signature = URL_SAFE_NO_PAD.encode(hmac::sign(&key.key, payload.as_bytes()));
+        assert_eq!(key.verify(&format!("{payload}.{signature}")), None);
+    }
+
+    #[test]
+    fn failed_logins() {
+        let failed_logins = FailedLogins::default();
+        let (client, other) = ("127.0.0.1".parse().unwrap(), "127.0.0.2".parse().unwrap());
+        for _ in 0..FailedLogins::MAX_FAILURES {
+            assert!(!failed_logins.is_blocked(client));
+            failed_logins.failed(client);
+        }
+        assert!(failed_logins.is_blocked(client));
+        assert!(!failed_logins.is_blocked(other));
+
+        failed_logins.succeeded(client);
+        assert!(!failed_logins.is_blocked(client));
+    }
+}
@@ -1,22 +1,51 @@
-use std::collections::HashMap;
-use std::io::{BufRead, BufReader, Seek, SeekFrom};
-use std::path::PathBuf;
+use std::borrow::Cow;
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{File, TryLockError};
+use std::io::{BufRead, BufReader, Seek, SeekFrom, Write};
+use std::num::NonZeroUsize;
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
 use std::time::{Duration, Instant};
-use std::{fs, io};
+use std::{fmt, fs, io, thread};
 
-use clap::Parser;
+use anyhow::Context;
+use clap::{Parser, Subcommand};
+use croissantine::auth::hash_password;
 use croissantine::available_docids_iter::AvailableDocIds;
+use croissantine::cdx::{CdxPages, CdxRecord, UrlPattern};
+use croissantine::charset::decode_html;
 use croissantine::database::Database;
-use croissantine::task::Task;
+use croissantine::document::{DocumentMetadata, DuplicatePolicy};
+use croissantine::http::{
+    mime_type, parse_status_range, HttpError, InclusionPolicy, ResponseHead, SkipReason,
+};
+use croissantine::progress::{Stage, TaskProgress, TaskReport};
+use croissantine::source::{self, DataSource};
+use croissantine::task::{
+    is_crawl_id, CdxQuery, CdxSource, Priority, Task, TaskKey, TaskKind, WarcPaths,
+};
 use croissantine::text::cleanup_chars;
+use croissantine::text::simhash::SimHasher;
 use croissantine::text::trigrams::TriGrams;
+use croissantine::wakeup;
+use croissantine::wat::{wat_url_for_warc, WatMetadata};
 use croissantine::{encode_trigram, DATABASE_MAX_SIZE};
 use flate2::bufread::GzDecoder;
 use flate2::read::MultiGzDecoder;
-use heed::EnvOpenOptions;
-use httparse::{Response, Status, EMPTY_HEADER};
-use rayon::prelude::{ParallelBridge, ParallelIterator};
+use heed::{EnvOpenOptions, RwTxn};
+use rand::rngs::StdRng;
+use rand::seq::IteratorRandom;
+use rand::SeedableRng;
+use rayon::prelude::{
+    IntoParallelIterator, IntoParallelRefIterator, ParallelBridge, ParallelIterator,
+};
 use roaring::RoaringTreemap;
+use signal_hook::consts::TERM_SIGNALS;
+use signal_hook::flag;
+use signal_hook::iterator::Signals;
 use url::Url;
 use warc::{RecordType, WarcHeader};
 
@@ -26,177 +55,1388 @@ struct Options {
     /// The database path where the indexed data is stored.
     #[arg(long, default_value = "croissantine.db")]
     database_path: PathBuf,
+
+    /// Also fetch the WAT files paired with the WARC files to enrich
+    /// the documents metadata and collect their outgoing links.
+    #[arg(long)]
+    with_wat: bool,
+
+    /// The HTTP status codes of the responses to index, e.g. `2xx`, `200-299` or `200`.
+    #[arg(long = "status", default_values = ["2xx"], value_parser = parse_status_range)]
+    statuses: Vec<RangeInclusive<u16>>,
+
+    /// The content types of the responses to index.
+    #[arg(long = "content-type", default_values = ["text/html", "application/xhtml+xml"])]
+    content_types: Vec<String>,
+
+    /// The version to keep when a URL that is already indexed is seen again.
+    #[arg(long, value_enum, default_value_t)]
+    duplicates: DuplicatePolicy,
+
+    /// The number of WARC files downloaded and extracted at the same time,
+    /// the documents of every file are kept in memory until they are written.
+    #[arg(long, default_value = "4")]
+    jobs: NonZeroUsize,
+
+    /// The number of seconds to wait before looking for new tasks when the queue
+    /// is empty, the search server also wakes the indexer up when it enqueues one.
+    #[arg(long, default_value_t = 300)]
+    poll_interval: u64,
+
+    /// The base URL of the crawl files, the paths listed in the `warc.paths.gz`
+    /// files and the CDX indexes are relative to it. It can be a mirror served
+    /// over HTTP or a local copy, e.g. `file:///data/commoncrawl/`.
+    #[arg(long, default_value = DataSource::COMMON_CRAWL)]
+    data_source: DataSource,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Processes the enqueued tasks forever, this is the default.
+    Run,
+    /// Enqueues a WARC or WET file that is already on disk, Gzipped or not.
+    Add {
+        /// The path to the WARC file to index.
+        path: PathBuf,
+        /// The priority of the task in the queue.
+        #[arg(long, value_enum, default_value_t)]
+        priority: Priority,
+    },
+    /// Enqueues all the WARC and WET files found in a directory.
+    IndexDir {
+        /// The directory containing the `.warc(.gz)` and `.wet(.gz)` files to index.
+        dir: PathBuf,
+        /// The priority of the tasks in the queue.
+        #[arg(long, value_enum, default_value_t)]
+        priority: Priority,
+    },
+    /// Samples indexed documents and verifies that their URL, title and postings agree.
+    ///
+    /// All the content postings are read to find the documents missing from them.
+    Check {
+        /// The number of documents to check.
+        #[arg(long, default_value_t = 1000)]
+        sample: usize,
+        /// The seed used to sample the documents, random by default.
+        #[arg(long)]
+        seed: Option<u64>,
+    },
+    /// Enqueues the indexing of the records of the URLs matching a pattern,
+    /// looked up in the CDX index of a crawl or in a local CDX file.
+    Cdx {
+        /// A URL pattern like `*.gouv.fr`, `example.org/blog/*` or `example.org/about`.
+        pattern: String,
+        /// The crawl to look into with the index server, e.g. `CC-MAIN-2023-23`.
+        #[arg(long, required_unless_present = "cdx_file", conflicts_with = "cdx_file")]
+        crawl: Option<String>,
+        /// The CDX index server, it serves a `{crawl}-index` collection by crawl.
+        #[arg(long, default_value = "https://index.commoncrawl.org/")]
+        index_server: Url,
+        /// A local CDX file, Gzipped or not, to use instead of the index server.
+        #[arg(long)]
+        cdx_file: Option<PathBuf>,
+        /// The maximum number of records to fetch.
+        #[arg(long, default_value_t = CdxQuery::DEFAULT_LIMIT)]
+        limit: usize,
+        /// The priority of the task in the queue.
+        #[arg(long, value_enum, default_value_t)]
+        priority: Priority,
+    },
+    /// Renumbers the indexed documents so that the docids are dense.
+    Compact,
+    /// Reads a password on the standard input and prints the hash to put
+    /// in the admin credentials of the search server, e.g. `admin:{hash}`.
+    HashPassword,
+    /// Creates an API token to use the admin routes of the search server.
+    CreateToken {
+        /// The name of the admin or service using the token.
+        name: String,
+    },
+    /// Revokes all the API tokens created with a name.
+    RevokeTokens {
+        /// The name the tokens were created with.
+        name: String,
+    },
+    /// Prints the click-through rate and mean reciprocal rank of the most searched queries.
+    ClickReport {
+        /// The number of queries to print.
+        #[arg(long, default_value_t = 50)]
+        limit: usize,
+    },
 }
 
 fn main() -> anyhow::Result<()> {
-    let Options { database_path } = Options::parse();
+    let Options {
+        database_path,
+        with_wat,
+        statuses,
+        content_types,
+        duplicates,
+        jobs,
+        poll_interval,
+        data_source,
+        command,
+    } = Options::parse();
+    let content_types = content_types.iter().map(|ct| mime_type(ct)).collect();
+    let policy = InclusionPolicy { statuses, content_types };
+
+    // Hashing a password doesn't need the database
+    if let Some(Command::HashPassword) = command {
+        let mut password = String::new();
+        io::stdin().read_line(&mut password)?;
+        let password = password.trim_end_matches(['\r', '\n']);
+        anyhow::ensure!(!password.is_empty(), "the password cannot be empty");
+        println!("{}", hash_password(password));
+        return Ok(());
+    }
 
     let mut options = EnvOpenOptions::new();
     options.map_size(DATABASE_MAX_SIZE);
     fs::create_dir_all(&database_path)?;
-    let database = Database::open_or_create(options, database_path)?;
+    let database = Database::open_or_create(options, &database_path)?;
+
+    match command.unwrap_or(Command::Run) {
+        Command::Run => {
+            let poll_interval = Duration::from_secs(poll_interval);
+            let jobs = jobs.get();
+            let settings =
+                Settings { with_wat, policy, duplicates, jobs, poll_interval, data_source };
+            run(&database, &database_path, &settings)
+        }
+        Command::Add { path, priority } => {
+            enqueue_local_warcs(&database, &database_path, priority, [path])
+        }
+        Command::IndexDir { dir, priority } => {
+            let mut paths = Vec::new();
+            for result in fs::read_dir(dir)? {
+                let path = result?.path();
+                let name = path.file_name().and_then(|n| n.to_str()).unwrap_or_default();
+                let extensions = [".warc", ".warc.gz", ".wet", ".wet.gz"];
+                if path.is_file() && extensions.iter().any(|ext| name.ends_with(ext)) {
+                    paths.push(path);
+                }
+            }
+            paths.sort_unstable();
+            enqueue_local_warcs(&database, &database_path, priority, paths)
+        }
+        Command::Cdx { pattern, crawl, index_server, cdx_file, limit, priority } => {
+            let source = match (cdx_file, crawl) {
+                (Some(path), _) => CdxSource::File(fs::canonicalize(path)?),
+                (None, Some(crawl)) => {
+                    // The crawl id is part of the URL of the index server
+                    anyhow::ensure!(
+                        is_crawl_id(&crawl),
+                        "{crawl:?} is not a crawl id like CC-MAIN-2023-23"
+                    );
+                    CdxSource::Server(index_server.join(&format!("{crawl}-index"))?)
+                }
+                (None, None) => anyhow::bail!("either a crawl or a CDX file is required"),
+            };
+            let query = CdxQuery { pattern, source, limit: Some(limit) };
+            enqueue_tasks(
+                &database,
+                &database_path,
+                [Task::new(priority, TaskKind::CdxQuery(query))],
+            )
+        }
+        Command::Check { sample, seed } => check_consistency(&database, sample, seed),
+        Command::Compact => {
+            let before = Instant::now();
+            let mut wtxn = database.write_txn()?;
+            let Some(renumbered) = database.compact_docids(&mut wtxn)? else {
+                anyhow::bail!("The indexer is deleting documents, compact once it's done");
+            };
+            wtxn.commit()?;
+            eprintln!("Renumbered {renumbered} documents in {:.02?}", before.elapsed());
+            Ok(())
+        }
+        Command::HashPassword => unreachable!("handled before opening the database"),
+        Command::CreateToken { name } => {
+            let mut wtxn = database.write_txn()?;
+            let token = database.create_api_token(&mut wtxn, &name)?;
+            wtxn.commit()?;
+            eprintln!("Created a token for {name}, it will not be shown again:");
+            println!("{token}");
+            Ok(())
+        }
+        Command::RevokeTokens { name } => {
+            let mut wtxn = database.write_txn()?;
+            let revoked = database.revoke_api_tokens(&mut wtxn, &name)?;
+            wtxn.commit()?;
+            eprintln!("Revoked {revoked} tokens of {name}");
+            Ok(())
+        }
+        Command::ClickReport { limit } => {
+            let rtxn = database.read_txn()?;
+            let mut stats: Vec<_> = database.query_stats(&rtxn)?.into_iter().collect();
+            stats.sort_by_key(|(_, stats)| Reverse(stats.searches));
+            println!("{:>8} {:>8} {:>6} {:>6}  query", "searches", "clicks", "ctr", "mrr");
+            for (query, stats) in stats.iter().take(limit) {
+                let (ctr, mrr) = (stats.click_through_rate(), stats.mean_reciprocal_rank());
+                println!(
+                    "{:>8} {:>8} {ctr:>6.3} {mrr:>6.3}  {query}",
+                    stats.searches, stats.clicks
+                );
+            }
+            Ok(())
+        }
+    }
+}
+
+fn check_consistency(database: &Database, sample: usize, seed: Option<u64>) -> anyhow::Result<()> {
+    let rtxn = database.read_txn()?;
+    let all_docids = database.all_docids(&rtxn)?;
+    // The deleted documents stay in the postings until the indexer purges them
+    let known_docids = &all_docids | &database.deleted_docids(&rtxn)?;
+    let mut rng = match seed {
+        Some(seed) => StdRng::seed_from_u64(seed),
+        None => StdRng::from_entropy(),
+    };
+
+    // The text isn't stored so we only know which documents are in the content postings
+    let mut content_docids = RoaringTreemap::new();
+    let mut unknown_content_postings = 0;
+    for result in database.content_ngrams_docids.iter(&rtxn)? {
+        let (_, bitmap) = result?;
+        if !bitmap.is_subset(&known_docids) {
+            unknown_content_postings += 1;
+        }
+        content_docids |= bitmap;
+    }
+
+    let docids = all_docids.iter().choose_multiple(&mut rng, sample);
+    let checked = docids.len();
+    let mut inconsistents = 0;
+    let mut key = String::new();
+    for docid in docids {
+        let mut problems = Vec::new();
+        match database.docid_uri.get(&rtxn, &docid)? {
+            Some(uri) => {
+                if let Err(e) = Url::parse(uri) {
+                    problems.push(format!("invalid URL {uri:?} ({e})"));
+                }
+                match database.uri_docid(&rtxn, uri)? {
+                    Some(uri_docid) if uri_docid == docid => (),
+                    Some(uri_docid) => problems.push(format!("{uri:?} resolves to {uri_docid}")),
+                    None => problems.push(format!("{uri:?} doesn't resolve to a docid")),
+                }
+            }
+            None => problems.push(String::from("missing URL")),
+        }
+
+        // Documents indexed before we stored the metadata don't have a title
+        let metadata = database.docid_metadata.get(&rtxn, &docid)?;
+        if let Some(title) = metadata.as_ref().and_then(|m| m.title.as_deref()) {
+            for trigram in TriGrams::new(cleanup_chars(title.chars())) {
+                let key = encode_trigram(&mut key, trigram);
+                let bitmap = database.title_ngrams_docids.get(&rtxn, key)?.unwrap_or_default();
+                if !bitmap.contains(docid) {
+                    problems.push(format!("missing from the {key:?} title postings"));
+                }
+                if !bitmap.is_subset(&known_docids) {
+                    problems.push(format!("the {key:?} title postings contain unknown docids"));
+                }
+            }
+        }
+
+        // Any text, even a single character, produces trigrams
+        let text_length = metadata.as_ref().and_then(|m| m.text_length).unwrap_or_default();
+        if text_length > 0 && !content_docids.contains(docid) {
+            problems.push(String::from("missing from the content postings"));
+        }
+
+        if !problems.is_empty() {
+            inconsistents += 1;
+            eprintln!("Document {docid} is inconsistent: {}", problems.join(", "));
+        }
+    }
+
+    anyhow::ensure!(
+        unknown_content_postings == 0,
+        "{unknown_content_postings} content postings contain unknown docids"
+    );
+    anyhow::ensure!(
+        inconsistents == 0,
+        "{inconsistents} out of {checked} documents are inconsistent"
+    );
+    eprintln!("All the {checked} sampled documents are consistent");
+
+    Ok(())
+}
+
+fn enqueue_local_warcs(
+    database: &Database,
+    database_path: &Path,
+    priority: Priority,
+    paths: impl IntoIterator<Item = PathBuf>,
+) -> anyhow::Result<()> {
+    let mut tasks = Vec::new();
+    for path in paths {
+        let path = fs::canonicalize(path)?;
+        tasks.push(Task::new(priority, TaskKind::LocalWarc(path)));
+    }
+    enqueue_tasks(database, database_path, tasks)
+}
+
+fn enqueue_tasks(
+    database: &Database,
+    database_path: &Path,
+    tasks: impl IntoIterator<Item = Task>,
+) -> anyhow::Result<()> {
+    let mut wtxn = database.write_txn()?;
+    for task in tasks {
+        let key = database.enqueue_task(&mut wtxn, &task)?;
+        eprintln!("Enqueuing {task} as task {}", key.id);
+    }
+    wtxn.commit()?;
+    wakeup::notify_indexer(database_path);
+    Ok(())
+}
+
+/// The indexing settings given on the command line.
+struct Settings {
+    with_wat: bool,
+    policy: InclusionPolicy,
+    duplicates: DuplicatePolicy,
+    /// The maximum number of indexing tasks processed concurrently.
+    jobs: usize,
+    /// The time to wait for a wakeup when the queue is empty.
+    poll_interval: Duration,
+    /// Where the crawl files are fetched from.
+    data_source: DataSource,
+}
+
+/// The progress of a task, updated by the threads processing it
+/// and regularly saved so that the indexer page can display it.
+struct Progress {
+    /// The progress and when its current stage started.
+    state: Mutex<(TaskProgress, Instant)>,
+    records: AtomicU64,
+}
+
+impl Progress {
+    fn new() -> Progress {
+        Progress {
+            state: Mutex::new((TaskProgress::new(), Instant::now())),
+            records: AtomicU64::new(0),
+        }
+    }
+
+    /// Ends the current stage, if any, and starts the next one.
+    fn stage(&self, stage: Option<Stage>) {
+        let mut state = self.state.lock().unwrap();
+        let (progress, started) = &mut *state;
+        if let Some(current) = progress.stage {
+            progress.timings.push((current, started.elapsed()));
+        }
+        progress.stage = stage;
+        *started = Instant::now();
+    }
+
+    fn downloaded(&self, bytes: u64) {
+        self.state.lock().unwrap().0.bytes_downloaded += bytes;
+    }
+
+    fn indexed(&self, documents: u64) {
+        self.state.lock().unwrap().0.documents_indexed += documents;
+    }
+
+    fn record_processed(&self) {
+        self.records.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn snapshot(&self) -> TaskProgress {
+        let mut progress = self.state.lock().unwrap().0.clone();
+        progress.records_processed = self.records.load(Ordering::Relaxed);
+        progress
+    }
+
+    /// Ends the last stage and returns the final progress.
+    fn finish(&self) -> TaskProgress {
+        self.stage(None);
+        self.snapshot()
+    }
+}
+
+/// How often the progress of the processing tasks is saved, every save takes
+/// the write transaction the search server needs to log the searches.
+const PROGRESS_SAVE_INTERVAL: Duration = Duration::from_secs(15);
+
+/// Saves the progress of the tasks regularly until the sender is dropped,
+/// the final progress is saved with the report of the tasks.
+fn save_progress(database: &Database, tasks: &[(TaskKey, &Progress)], done: mpsc::Receiver<()>) {
+    while let Err(mpsc::RecvTimeoutError::Timeout) = done.recv_timeout(PROGRESS_SAVE_INTERVAL) {
+        let result = database.write_txn().and_then(|mut wtxn| {
+            for (key, progress) in tasks {
+                database.progress.put(&mut wtxn, key, &progress.snapshot())?;
+            }
+            wtxn.commit()
+        });
+        if let Err(e) = result {
+            eprintln!("Failed to save the progress: {e}");
+        }
+    }
+}
+
+fn run(database: &Database, database_path: &Path, settings: &Settings) -> anyhow::Result<()> {
+    let _lock = lock_indexer(database_path)?;
+    let mut wtxn = database.write_txn()?;
+    // Databases created before the reverse mapping existed must be migrated
+    if database.uri_digest_docid.is_empty(&wtxn)? && !database.docid_uri.is_empty(&wtxn)? {
+        eprintln!("Building the URL to docid mapping...");
+        database.rebuild_uri_docid(&mut wtxn)?;
+    }
+    // The queue used before the tasks had a priority must be migrated
+    let migrated = database.migrate_legacy_tasks(&mut wtxn)?;
+    if migrated != 0 {
+        eprintln!("Migrated {migrated} tasks to the new queue");
+    }
+    // The documents deleted before we stopped may still be in the postings
+    let purged = database.purge_deleted_docids(&mut wtxn)?;
+    if purged != 0 {
+        eprintln!("Purged {purged} deleted documents from the postings");
+    }
+    // The tasks we were processing when we stopped must be processed again
+    let requeued = database.requeue_processing_tasks(&mut wtxn)?;
+    if requeued != 0 {
+        eprintln!("Requeued {requeued} tasks that were interrupted");
+    }
+    wtxn.commit()?;
+
+    // The search server wakes us up when it enqueues a task and the
+    // termination signals stop us once the current tasks are processed.
+    let (sender, wakeups) = mpsc::channel();
+    wakeup::listen_wakeups(database_path, sender.clone())?;
+    let shutdown = Arc::new(AtomicBool::new(false));
+    for signal in TERM_SIGNALS {
+        // A second signal terminates the indexer immediately
+        flag::register_conditional_shutdown(*signal, 1, Arc::clone(&shutdown))?;
+        flag::register(*signal, Arc::clone(&shutdown))?;
+    }
+    let mut signals = Signals::new(TERM_SIGNALS)?;
+    thread::spawn(move || {
+        for _ in signals.forever() {
+            let _ = sender.send(());
+        }
+    });
 
-    loop {
+    while !shutdown.load(Ordering::Relaxed) {
+        // The search server wakes us up when it resumes the indexer
+        if database.is_paused(&database.read_txn()?)? {
+            eprintln!("The indexer is paused, waiting...");
+            let _ = wakeups.recv_timeout(settings.poll_interval);
+            continue;
+        }
+
+        // We only hold the write lock to claim the tasks, not to process them
         let mut wtxn = database.write_txn()?;
-        let mut tasks = database.enqueued.iter(&wtxn)?;
-        let (task_id, task) = match tasks.next().transpose()? {
-            Some(entry) => entry,
+        let batch = claim_batch(database, &mut wtxn, settings.jobs)?;
+        wtxn.commit()?;
+
+        let first_indexing = batch.first().map(|(_, task)| task.kind.is_indexing());
+        match first_indexing {
             None => {
-                drop(tasks);
-                wtxn.abort();
-                eprintln!("No new task found to process, waiting 5h...");
-                std::thread::sleep(Duration::from_secs(5 * 60 * 60)); // 5 hours
-                continue;
+                eprintln!("No new task found to process, waiting...");
+                let _ = wakeups.recv_timeout(settings.poll_interval);
             }
-        };
+            Some(false) => {
+                for (key, task) in batch {
+                    process_task(database, key, task, settings)?;
+                }
+            }
+            Some(true) => index_batch(database, batch, settings)?,
+        }
 
-        drop(tasks);
-
-        let url = task.url();
-        let request = ureq::get(url.as_str()).call()?;
-        let mut reader = request.into_reader();
-        let mut file = tempfile::tempfile()?;
-        let before = Instant::now();
-        let length = io::copy(&mut reader, &mut file)?;
-        file.seek(SeekFrom::Start(0))?;
-        let reader = BufReader::new(file);
-
-        match task {
-            Task::WarcUrlPaths(_) => {
-                eprintln!(
-                    "Fetched the WARC path file ({length} bytes) in {:.02?}",
-                    before.elapsed()
-                );
-                // The WarcUrls have always incrementing ids while the WarcUrlPaths
-                // always decrementing ones. We always processes tasks from the
-                // smallest to the biggests.
-                let uncompressed = BufReader::new(GzDecoder::new(reader));
-                for (i, result) in uncompressed.lines().enumerate() {
-                    let path = result?;
-                    if !path.is_empty() {
-                        let url = Url::parse(&format!("https://data.commoncrawl.org/{}", path))?;
-                        let key: u32 = i.try_into().unwrap();
-                        database.enqueued.put(&mut wtxn, &key, &Task::WarcUrl(url))?;
+        purge_deleted_documents(database)?;
+    }
+
+    eprintln!("Received a termination signal, stopping...");
+    let _ = fs::remove_file(wakeup::socket_path(database_path));
+    Ok(())
+}
+
+/// Makes sure we are the only indexer running on the database until the returned file
+/// is dropped. We requeue the processing tasks and take the wakeup socket when we start,
+/// another indexer running on the same database would lose them.
+fn lock_indexer(database_path: &Path) -> anyhow::Result<File> {
+    let lock = File::create(database_path.join("indexer.lock"))?;
+    match lock.try_lock() {
+        Ok(()) => Ok(lock),
+        Err(TryLockError::WouldBlock) => {
+            anyhow::bail!("Another indexer is running on {}", database_path.display())
+        }
+        Err(TryLockError::Error(e)) => Err(e.into()),
+    }
+}
+
+/// Removes the deleted documents from the postings once there are enough of them,
+/// the purge scans all the postings while holding the write transaction. Until then
+/// the searches ignore them and their docids are not given to other documents.
+fn purge_deleted_documents(database: &Database) -> anyhow::Result<()> {
+    if !database.should_purge_deleted_docids(&database.read_txn()?)? {
+        return Ok(());
+    }
+
+    let before = Instant::now();
+    let mut wtxn = database.write_txn()?;
+    let purged = database.purge_deleted_docids(&mut wtxn)?;
+    wtxn.commit()?;
+    eprintln!("Purged {purged} deleted documents in {:.02?}", before.elapsed());
+    Ok(())
+}
+
+/// Claims the first enqueued task and, if it is an indexing task, the indexing
+/// tasks that directly follow it, we never skip over another kind of task
+/// to preserve the order of the queue.
+fn claim_batch(
+    database: &Database,
+    wtxn: &mut RwTxn,
+    jobs: usize,
+) -> anyhow::Result<Vec<(TaskKey, Task)>> {
+    let mut keys = Vec::new();
+    let mut indexing = false;
+    for result in database.enqueued.iter(wtxn)? {
+        let (key, task) = result?;
+        let is_indexing = task.kind.is_indexing();
+        match keys.first() {
+            None => indexing = is_indexing,
+            Some(_) if indexing && is_indexing && keys.len() < jobs => (),
+            Some(_) => break,
+        }
+        keys.push(key);
+    }
+
+    let mut batch = Vec::with_capacity(keys.len());
+    for key in keys {
+        if let Some(task) = database.claim_task(wtxn, key)? {
+            batch.push((key, task));
+        }
+    }
+    Ok(batch)
+}
+
+/// Downloads and extracts the files of claimed indexing tasks concurrently
+/// and writes the documents of each of them in a short write transaction.
+fn index_batch(
+    database: &Database,
+    batch: Vec<(TaskKey, Task)>,
+    settings: &Settings,
+) -> anyhow::Result<()> {
+    let progresses: Vec<_> = batch.iter().map(|_| Progress::new()).collect();
+    let outputs: Vec<_> = thread::scope(|s| {
+        let (sender, done) = mpsc::channel::<()>();
+        let tasks: Vec<_> = batch.iter().map(|(key, _)| *key).zip(&progresses).collect();
+        s.spawn(move || save_progress(database, &tasks, done));
+
+        let handles: Vec<_> = batch
+            .iter()
+            .zip(&progresses)
+            .map(|((_, task), progress)| s.spawn(|| extract_task(task, settings, progress)))
+            .collect();
+        // A panicking extraction only fails its own task
+        let outputs = handles
+            .into_iter()
+            .map(|h| h.join().unwrap_or_else(|_| Err(anyhow::anyhow!("the extraction panicked"))))
+            .collect();
+        drop(sender);
+        outputs
+    });
+
+    let before = Instant::now();
+    for (((key, task), result), progress) in batch.into_iter().zip(outputs).zip(progresses) {
+        finish_indexing_task(database, key, task, result, &progress, settings)?;
+    }
+    eprintln!("Committed the batch in {:.02?}", before.elapsed());
+
+    Ok(())
+}
+
+/// Writes the documents extracted by an indexing task and finishes it in its own
+/// transaction, a task that cannot be extracted or written is recorded as failed
+/// rather than blocking the queue.
+fn finish_indexing_task(
+    database: &Database,
+    key: TaskKey,
+    task: Task,
+    result: anyhow::Result<IndexingOutput>,
+    progress: &Progress,
+    settings: &Settings,
+) -> anyhow::Result<()> {
+    let written = result.and_then(|output| {
+        eprintln!("Writing the documents of {task}...");
+        progress.stage(Some(Stage::Writing));
+        let mut wtxn = database.write_txn()?;
+        let written = write_output(database, &mut wtxn, output, settings.duplicates)?;
+        progress.indexed(written);
+        Ok(wtxn)
+    });
+    let (mut wtxn, report) = match written {
+        Ok(wtxn) => (wtxn, TaskReport::new(task, progress.finish())),
+        // The failed tasks are not retried, they can be enqueued again
+        Err(e) => {
+            eprintln!("Failed to index {task}: {e:#}");
+            let report = TaskReport::failed(task, progress.finish(), format!("{e:#}"));
+            (database.write_txn()?, report)
+        }
+    };
+    database.finish_task(&mut wtxn, key, &report)?;
+    wtxn.commit()?;
+    Ok(())
+}
+
+/// Processes a claimed task that doesn't index a WARC file, a failed
+/// task is recorded in the history with its error.
+fn process_task(
+    database: &Database,
+    key: TaskKey,
+    task: Task,
+    settings: &Settings,
+) -> anyhow::Result<()> {
+    let progress = Progress::new();
+    let (mut wtxn, report) = match apply_task(database, &task, settings, &progress) {
+        Ok(wtxn) => (wtxn, TaskReport::new(task, progress.finish())),
+        Err(e) => {
+            eprintln!("Failed to process {task}: {e:#}");
+            let report = TaskReport::failed(task, progress.finish(), format!("{e:#}"));
+            (database.write_txn()?, report)
+        }
+    };
+    database.finish_task(&mut wtxn, key, &report)?;
+    wtxn.commit()?;
+    Ok(())
+}
+
+/// Processes a task that doesn't index a WARC file and returns
+/// the uncommitted changes, the task must be finished with them.
+fn apply_task<'a>(
+    database: &'a Database,
+    task: &Task,
+    settings: &Settings,
+    progress: &Progress,
+) -> anyhow::Result<RwTxn<'a>> {
+    let before = Instant::now();
+    match &task.kind {
+        TaskKind::WarcUrlPaths(warc_paths) => {
+            let WarcPaths { url, selection } = warc_paths;
+            progress.stage(Some(Stage::Downloading));
+            let (length, reader) = download(url, progress)?;
+            eprintln!("Fetched the WARC path file ({length} bytes) in {:.02?}", before.elapsed());
+            let uncompressed = BufReader::new(GzDecoder::new(reader));
+            let mut paths: Vec<_> = uncompressed.lines().collect::<io::Result<_>>()?;
+            paths.retain(|p| !p.is_empty());
+            let selected = selection.select(&paths);
+            eprintln!("Selected {} out of {} paths", selected.len(), paths.len());
+
+            let mut files = Vec::with_capacity(selected.len());
+            for path in selected {
+                let url = settings.data_source.url(path)?;
+                files.push(if path.ends_with(".wet.gz") {
+                    TaskKind::WetUrl(url)
+                } else {
+                    TaskKind::WarcUrl(url)
+                });
+            }
+
+            // The files are enqueued after the other tasks with the same priority
+            progress.stage(Some(Stage::Writing));
+            let mut wtxn = database.write_txn()?;
+            database.enqueue_crawl_files(&mut wtxn, warc_paths, task.priority, files)?;
+            Ok(wtxn)
+        }
+        TaskKind::DeleteDocuments(docids) => {
+            progress.stage(Some(Stage::Writing));
+            let docids = RoaringTreemap::from_iter(docids.iter().copied());
+            let mut wtxn = database.write_txn()?;
+            database.delete_documents(&mut wtxn, &docids)?;
+            eprintln!("Deleted {} documents in {:.02?}", docids.len(), before.elapsed());
+            Ok(wtxn)
+        }
+        kind @ (TaskKind::WarcUrl(_)
+        | TaskKind::WetUrl(_)
+        | TaskKind::LocalWarc(_)
+        | TaskKind::CdxQuery(_)) => {
+            anyhow::bail!("{kind} must be processed as an indexing task")
+        }
+    }
+}
+
+/// Downloads or opens the WARC or WET file of an indexing task and extracts
+/// its documents, this doesn't touch the database.
+fn extract_task(
+    task: &Task,
+    settings: &Settings,
+    progress: &Progress,
+) -> anyhow::Result<IndexingOutput> {
+    let Settings { with_wat, ref policy, ref data_source, .. } = *settings;
+
+    match &task.kind {
+        // The CommonCrawl Gzipped WARC file to analyze
+        TaskKind::WarcUrl(url) => {
+            let before = Instant::now();
+            progress.stage(Some(Stage::Downloading));
+            let (length, reader) = download(url, progress)?;
+            eprintln!("Fetched the WARC file ({length} bytes) in {:.02?}", before.elapsed());
+            let wat = match wat_url_for_warc(url) {
+                Some(wat_url) if with_wat => fetch_wat(&wat_url, progress),
+                _ => HashMap::new(),
+            };
+            progress.stage(Some(Stage::Extracting));
+            let uncompressed = BufReader::new(MultiGzDecoder::new(reader));
+            let context = RecordContext { format: RecordFormat::Warc, wat: &wat, policy };
+            Ok(extract_warc(uncompressed, &context, progress))
+        }
+        // The CommonCrawl Gzipped WET file, the plain text extracted from the WARCs
+        TaskKind::WetUrl(url) => {
+            let before = Instant::now();
+            progress.stage(Some(Stage::Downloading));
+            let (length, reader) = download(url, progress)?;
+            eprintln!("Fetched the WET file ({length} bytes) in {:.02?}", before.elapsed());
+            progress.stage(Some(Stage::Extracting));
+            let uncompressed = BufReader::new(MultiGzDecoder::new(reader));
+            let wat = HashMap::new();
+            let context = RecordContext { format: RecordFormat::Wet, wat: &wat, policy };
+            Ok(extract_warc(uncompressed, &context, progress))
+        }
+        // A WARC or WET file that is already on disk
+        TaskKind::LocalWarc(path) => {
+            eprintln!("Opening the local WARC file {}", path.display());
+            progress.stage(Some(Stage::Extracting));
+            let file = File::open(path)?;
+            progress.downloaded(file.metadata()?.len());
+            let reader = BufReader::new(file);
+            let name = path.file_name().and_then(|n| n.to_str()).unwrap_or_default();
+            let format = if name.contains(".wet") { RecordFormat::Wet } else { RecordFormat::Warc };
+            // The paired WAT file must be next to the WARC file: `{name}.warc.wat.gz`
+            let wat = match name.strip_suffix(".warc.gz") {
+                Some(stem) if with_wat && format == RecordFormat::Warc => {
+                    let wat_path = path.with_file_name(format!("{stem}.warc.wat.gz"));
+                    match File::open(&wat_path) {
+                        Ok(file) => read_wat(BufReader::new(MultiGzDecoder::new(file))),
+                        Err(e) => {
+                            eprintln!("Ignoring the WAT file {}: {e}", wat_path.display());
+                            HashMap::new()
+                        }
                     }
                 }
+                _ => HashMap::new(),
+            };
+            let context = RecordContext { format, wat: &wat, policy };
+            if path.extension().map_or(false, |ext| ext == "gz") {
+                let uncompressed = BufReader::new(MultiGzDecoder::new(reader));
+                Ok(extract_warc(uncompressed, &context, progress))
+            } else {
+                Ok(extract_warc(reader, &context, progress))
+            }
+        }
+        // The records of some URLs, fetched from the WARC files they are in
+        TaskKind::CdxQuery(query) => {
+            let before = Instant::now();
+            progress.stage(Some(Stage::Downloading));
+            let records = lookup_cdx(query, policy)?;
+            let (count, pattern) = (records.len(), &query.pattern);
+            eprintln!("Found {count} records matching {pattern} in {:.02?}", before.elapsed());
+            let reader = fetch_records(&records, data_source, progress)?;
+            progress.stage(Some(Stage::Extracting));
+            let uncompressed = BufReader::new(MultiGzDecoder::new(reader));
+            let wat = HashMap::new();
+            let context = RecordContext { format: RecordFormat::Warc, wat: &wat, policy };
+            Ok(extract_warc(uncompressed, &context, progress))
+        }
+        TaskKind::WarcUrlPaths(_) | TaskKind::DeleteDocuments(_) => {
+            anyhow::bail!("{task} is not an indexing task")
+        }
+    }
+}
 
-                // Remove the tasks now
-                database.enqueued.delete(&mut wtxn, &task_id)?;
-                wtxn.commit()?;
+/// Reads the CDX index and returns the records of the URLs matching the
+/// pattern of the query, we ignore the records the policy would skip.
+fn lookup_cdx(query: &CdxQuery, policy: &InclusionPolicy) -> anyhow::Result<Vec<CdxRecord>> {
+    let limit = query.limit.unwrap_or(CdxQuery::DEFAULT_LIMIT);
+    let mut records = Vec::new();
+    match &query.source {
+        CdxSource::Server(server) => {
+            let mut url = server.clone();
+            url.query_pairs_mut().append_pair("url", &query.pattern).append_pair("output", "json");
+
+            // The index server splits the matching URLs in pages, we request them one by one
+            let mut pages_url = url.clone();
+            pages_url.query_pairs_mut().append_pair("showNumPages", "true");
+            let pages = match ureq::get(pages_url.as_str()).call() {
+                Ok(response) => {
+                    let pages: CdxPages = serde_json::from_reader(response.into_reader())
+                        .with_context(|| format!("reading the number of pages of {pages_url}"))?;
+                    pages.pages
+                }
+                // The index server answers with a 404 when no URL matches
+                Err(ureq::Error::Status(404, _)) => 0,
+                Err(e) => return Err(e.into()),
+            };
+
+            for page in 0..pages {
+                let mut page_url = url.clone();
+                page_url.query_pairs_mut().append_pair("page", &page.to_string());
+                let response = ureq::get(page_url.as_str()).call()?;
+                let reader = BufReader::new(response.into_reader());
+                // The index server only returns the matching URLs
+                read_cdx_records(reader, None, policy, limit, &mut records)?;
+                if records.len() == limit {
+                    break;
+                }
             }
-            // The CommonCrawl Gzipped WARC file to analyze
-            Task::WarcUrl(_) => {
-                eprintln!("Fetched the WARC file ({length} bytes) in {:.02?}", before.elapsed());
-                let before = Instant::now();
-                let uncompressed = BufReader::new(MultiGzDecoder::new(reader));
-                let warc = warc::WarcReader::new(uncompressed);
-
-                let mut all_docids = database.all_docids(&wtxn)?;
-                let available_docids = AvailableDocIds::new(&all_docids);
-
-                let IndexingOutput { title_ngrams_docids, content_ngrams_docids, docids, urls } =
-                    warc.iter_records()
-                        .zip(available_docids)
-                        .par_bridge()
-                        .map(|(result, docid)| {
-                            let record = result.unwrap();
-                            let uri =
-                                match (record.warc_type(), record.header(WarcHeader::TargetURI)) {
-                                    (RecordType::Response, Some(uri)) => uri,
-                                    _ => return None,
-                                };
-
-                            let url = Url::parse(&uri).unwrap();
-                            let mut title_ngrams_docids = HashMap::<_, RoaringTreemap>::new();
-                            let mut content_ngrams_docids = HashMap::<_, RoaringTreemap>::new();
-
-                            let mut headers = [EMPTY_HEADER; 64];
-                            let mut req = Response::new(&mut headers);
-                            let http_body = record.body();
-                            if let Ok(Status::Complete(size)) = req.parse(http_body) {
-                                let html_body = &http_body[size..];
-                                let product =
-                                    readability::extractor::extract(&mut &html_body[..], &url)
-                                        .unwrap();
-
-                                for trigram in TriGrams::new(cleanup_chars(product.title.chars())) {
-                                    title_ngrams_docids.entry(trigram).or_default().insert(docid);
-                                }
-
-                                for trigram in TriGrams::new(cleanup_chars(product.text.chars())) {
-                                    content_ngrams_docids.entry(trigram).or_default().insert(docid);
-                                }
-                            }
-
-                            Some(IndexingOutput {
-                                title_ngrams_docids,
-                                content_ngrams_docids,
-                                docids: RoaringTreemap::from_iter([docid]),
-                                urls: vec![url],
-                            })
-                        })
-                        .flatten()
-                        .reduce(IndexingOutput::default, IndexingOutput::merge);
-
-                let count = docids.len();
-
-                eprintln!(
-                    "{count} documents seen in {:.02?}, will commit soon...",
-                    before.elapsed()
-                );
-                
-                let before_commit = Instant::now();
+        }
+        CdxSource::File(path) => {
+            let file = File::open(path)?;
+            let reader: Box<dyn BufRead> = if path.extension().is_some_and(|ext| ext == "gz") {
+                Box::new(BufReader::new(MultiGzDecoder::new(file)))
+            } else {
+                Box::new(BufReader::new(file))
+            };
+            // A file contains all the URLs, not only the matching ones
+            let pattern = UrlPattern::parse(&query.pattern);
+            read_cdx_records(reader, Some(&pattern), policy, limit, &mut records)?;
+        }
+    }
+
+    Ok(records)
+}
+
+/// Reads the lines of a CDX index until there are `limit` records,
+/// only keeps the records matching the pattern, if any, and the policy.
+fn read_cdx_records(
+    reader: impl BufRead,
+    pattern: Option<&UrlPattern>,
+    policy: &InclusionPolicy,
+    limit: usize,
+    records: &mut Vec<CdxRecord>,
+) -> io::Result<()> {
+    for result in reader.lines() {
+        if records.len() >= limit {
+            break;
+        }
+
+        let line = result?;
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let record = match CdxRecord::from_line(&line) {
+            Ok(record) => record,
+            Err(e) => {
+                eprintln!("Skipping CDX line: {e}");
+                continue;
+            }
+        };
+
+        let matches = match pattern {
+            Some(pattern) => Url::parse(&record.url).is_ok_and(|u| pattern.matches(&u)),
+            None => true,
+        };
+        let included =
+            record.status.is_some_and(|s| policy.check(s, record.mime.as_deref()).is_ok());
+        if matches && included {
+            records.push(record);
+        }
+    }
+
+    Ok(())
+}
+
+/// The number of records fetched at the same time, they are kept in
+/// memory until they are written to the temporary file.
+const FETCHED_RECORDS_CHUNK: usize = 64;
+
+/// Fetches the Gzipped records by byte ranges from the WARC files and
+/// concatenates them in a temporary file, it is a valid Gzipped WARC file.
+/// The downloaded bytes are counted as the records are fetched.
+fn fetch_records(
+    records: &[CdxRecord],
+    data_source: &DataSource,
+    progress: &Progress,
+) -> anyhow::Result<BufReader<File>> {
+    let before = Instant::now();
+    let mut file = tempfile::tempfile()?;
+    let mut length = 0;
+    let mut failed = 0;
+    for chunk in records.chunks(FETCHED_RECORDS_CHUNK) {
+        let fetched: Vec<anyhow::Result<Vec<u8>>> = chunk
+            .par_iter()
+            .map(|record| {
+                let url = data_source.url(&record.filename)?;
+                let bytes = source::read_range(&url, record.offset, record.length)
+                    .with_context(|| format!("fetching {url}"))?;
+                Ok(bytes)
+            })
+            .collect();
 
-                for (docid, url) in docids.iter().zip(urls) {
-                    database.docid_uri.put(&mut wtxn, &docid, url.as_str())?;
+        for result in fetched {
+            match result {
+                Ok(bytes) => {
+                    file.write_all(&bytes)?;
+                    length += bytes.len() as u64;
+                    progress.downloaded(bytes.len() as u64);
                 }
+                Err(e) => {
+                    failed += 1;
+                    eprintln!("Skipping a record: {e}");
+                }
+            }
+        }
+    }
+    file.seek(SeekFrom::Start(0))?;
+
+    let fetched = records.len() - failed;
+    eprintln!("Fetched {fetched} records ({length} bytes) in {:.02?}", before.elapsed());
+    Ok(BufReader::new(file))
+}
 
-                all_docids |= docids;
+/// Downloads the content at the given URL into a temporary file, or opens it
+/// for `file://` URLs, and returns its length along with a reader on it.
+fn download(url: &Url, progress: &Progress) -> anyhow::Result<(u64, BufReader<File>)> {
+    let (length, file) = source::download(url, |bytes| progress.downloaded(bytes))
+        .with_context(|| format!("fetching {url}"))?;
+    Ok((length, BufReader::new(file)))
+}
 
-                // Write everything into LMDB
-                database.put_all_docids(&mut wtxn, &all_docids)?;
+/// Downloads the WAT file and returns its metadata, an empty
+/// set of metadata is returned if the download failed.
+fn fetch_wat(url: &Url, progress: &Progress) -> HashMap<String, WatMetadata> {
+    let before = Instant::now();
+    match download(url, progress) {
+        Ok((length, reader)) => {
+            eprintln!("Fetched the WAT file ({length} bytes) in {:.02?}", before.elapsed());
+            read_wat(BufReader::new(MultiGzDecoder::new(reader)))
+        }
+        Err(e) => {
+            eprintln!("Ignoring the WAT file {url}: {e}");
+            HashMap::new()
+        }
+    }
+}
 
-                let mut key = String::new();
-                for (trigram, bitmap) in title_ngrams_docids {
-                    let before = database
-                        .title_ngrams_docids
-                        .get(&wtxn, encode_trigram(&mut key, trigram))?
-                        .unwrap_or_default();
-                    let bitmap = bitmap | before;
-                    database.title_ngrams_docids.put(&mut wtxn, &key, &bitmap)?;
+/// Reads the metadata records of an uncompressed WAT file and returns them
+/// by the id of the WARC record they refer to (the `WARC-Refers-To` header).
+fn read_wat<R: BufRead + Send>(reader: R) -> HashMap<String, WatMetadata> {
+    warc::WarcReader::new(reader)
+        .iter_records()
+        .par_bridge()
+        .filter_map(|result| {
+            let record = match result {
+                Ok(record) => record,
+                Err(e) => {
+                    eprintln!("Skipping WAT record: {e}");
+                    return None;
                 }
+            };
 
-                for (trigram, bitmap) in content_ngrams_docids {
-                    let before = database
-                        .content_ngrams_docids
-                        .get(&wtxn, encode_trigram(&mut key, trigram))?
-                        .unwrap_or_default();
-                    let bitmap = bitmap | before;
-                    database.content_ngrams_docids.put(&mut wtxn, &key, &bitmap)?;
+            if !matches!(record.warc_type(), RecordType::Metadata) {
+                return None;
+            }
+
+            let refers_to = record.header(WarcHeader::RefersTo)?.into_owned();
+            let base = Url::parse(&record.header(WarcHeader::TargetURI)?).ok()?;
+            match WatMetadata::from_json(record.body(), &base) {
+                Ok(metadata) => Some((refers_to, metadata)),
+                Err(e) => {
+                    eprintln!("Skipping WAT record {refers_to}: {e}");
+                    None
                 }
+            }
+        })
+        .collect()
+}
+
+/// The kind of records a WARC file contains.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum RecordFormat {
+    /// Raw HTTP responses from which we extract the content.
+    Warc,
+    /// Conversion records containing the already extracted plain text.
+    Wet,
+}
+
+/// Everything needed to decide whether and how a record is indexed.
+struct RecordContext<'a> {
+    format: RecordFormat,
+    /// The WAT metadata by the id of the WARC record they refer to.
+    wat: &'a HashMap<String, WatMetadata>,
+    policy: &'a InclusionPolicy,
+}
+
+/// Extracts the documents of all the response records of an uncompressed WARC file.
+fn extract_warc<R: BufRead + Send>(
+    reader: R,
+    context: &RecordContext,
+    progress: &Progress,
+) -> IndexingOutput {
+    let before = Instant::now();
+    let warc = warc::WarcReader::new(reader);
+
+    // Records are identified by their position in the file while being processed,
+    // docids are only given to the documents we keep, once everything is extracted.
+    let output = warc
+        .iter_records()
+        .zip(0..)
+        .par_bridge()
+        .map(|(result, record_id)| {
+            progress.record_processed();
+            match index_record(result, record_id, context) {
+                Ok(output) => output,
+                Err(error) => {
+                    eprintln!("Skipping record {record_id}: {error}");
+                    Some(IndexingOutput::from_error(&error))
+                }
+            }
+        })
+        .flatten()
+        .reduce(IndexingOutput::default, IndexingOutput::merge);
+
+    eprintln!("{} documents seen in {:.02?}", output.documents.len(), before.elapsed());
+    eprintln!("{}", output.errors);
+    eprintln!("{}", output.skipped);
+
+    output
+}
+
+/// Writes the documents extracted from a WARC file into the database and returns
+/// the number of documents written, the caller is responsible to commit the changes.
+fn write_output(
+    database: &Database,
+    wtxn: &mut RwTxn,
+    output: IndexingOutput,
+    duplicates: DuplicatePolicy,
+) -> anyhow::Result<u64> {
+    let IndexingOutput { title_ngrams_docids, content_ngrams_docids, mut documents, .. } = output;
+    let count = documents.len();
+    let before_commit = Instant::now();
+
+    // Every document carries its own record id, the order in which
+    // they have been reduced doesn't matter anymore.
+    documents.sort_unstable_by_key(|(record_id, _)| *record_id);
+    let unique = documents.windows(2).all(|w| w[0].0 != w[1].0);
+    anyhow::ensure!(unique, "the same record id was given to two documents");
+
+    // The documents we must remove because a better version of them has been
+    // seen and the new documents we don't keep because the indexed one is better.
+    // The deleted docids can't be reused while they are part of the postings
+    let used_docids = database.all_docids(wtxn)? | database.deleted_docids(wtxn)?;
+    let mut available_docids = AvailableDocIds::new(&used_docids);
+    let mut record_docids = Vec::with_capacity(count);
+    let mut replaced = RoaringTreemap::new();
+    let mut rejected = 0;
+    for (record_id, Document { url, metadata, outlinks }) in documents {
+        if let Some(old_docid) = database.uri_docid(wtxn, url.as_str())? {
+            let old_metadata = database.docid_metadata.get(wtxn, &old_docid)?.unwrap_or_default();
+            if duplicates.prefers(&metadata, &old_metadata) {
+                replaced.insert(old_docid);
+            } else {
+                rejected += 1;
+                continue;
+            }
+        }
+
+        let docid = available_docids.next().context("no more docids available")?;
+        record_docids.push((record_id, docid));
+
+        database.docid_uri.put(wtxn, &docid, url.as_str())?;
+        database.put_uri_docid(wtxn, url.as_str(), docid)?;
+        database.docid_metadata.put(wtxn, &docid, &metadata)?;
+        if !outlinks.is_empty() {
+            database.docid_outlinks.put(wtxn, &docid, &outlinks)?;
+        }
+    }
+
+    // The replaced documents can also be part of this batch
+    let before_deletion = Instant::now();
+    database.delete_documents(wtxn, &replaced)?;
+    eprintln!(
+        "{} documents replaced and {rejected} ignored as duplicates in {:.02?}",
+        replaced.len(),
+        before_deletion.elapsed()
+    );
 
-                // Remove the tasks now
-                database.enqueued.delete(&mut wtxn, &task_id)?;
-                wtxn.commit()?;
+    let mut docids: RoaringTreemap = record_docids.iter().map(|(_, docid)| *docid).collect();
+    docids -= &replaced;
+    let written = docids.len();
+    let all_docids = database.all_docids(wtxn)? | docids;
 
-                eprintln!("Writing into the database took {:.02?}", before_commit.elapsed());
-                eprintln!("Processed {count} documents in {:.02?}, committed!", before.elapsed());
+    // Write everything into LMDB
+    database.put_all_docids(wtxn, &all_docids)?;
+
+    let mut key = String::new();
+    for (trigram, mut bitmap) in remap_postings(title_ngrams_docids, &record_docids) {
+        bitmap -= &replaced;
+        if bitmap.is_empty() {
+            continue;
+        }
+        let before = database
+            .title_ngrams_docids
+            .get(wtxn, encode_trigram(&mut key, trigram))?
+            .unwrap_or_default();
+        let bitmap = bitmap | before;
+        database.title_ngrams_docids.put(wtxn, &key, &bitmap)?;
+    }
+
+    for (trigram, mut bitmap) in remap_postings(content_ngrams_docids, &record_docids) {
+        bitmap -= &replaced;
+        if bitmap.is_empty() {
+            continue;
+        }
+        let before = database
+            .content_ngrams_docids
+            .get(wtxn, encode_trigram(&mut key, trigram))?
+            .unwrap_or_default();
+        let bitmap = bitmap | before;
+        database.content_ngrams_docids.put(wtxn, &key, &bitmap)?;
+    }
+
+    eprintln!("Writing {count} documents took {:.02?}", before_commit.elapsed());
+
+    Ok(written)
+}
+
+/// Extracts the content of a single WARC record, returning `None` if
+/// the record is not a response (or a conversion for WET files)
+/// and must not be indexed.
+fn index_record(
+    result: Result<warc::Record<warc::BufferedBody>, warc::Error>,
+    record_id: u64,
+    context: &RecordContext,
+) -> Result<Option<IndexingOutput>, RecordError> {
+    let record = result.map_err(RecordError::Warc)?;
+    let RecordContext { format, wat, policy } = *context;
+    let uri = match (format, record.warc_type(), record.header(WarcHeader::TargetURI)) {
+        (RecordFormat::Warc, RecordType::Response, Some(uri)) => uri,
+        (RecordFormat::Wet, RecordType::Conversion, Some(uri)) => uri,
+        _ => return Ok(None),
+    };
+
+    let url = Url::parse(&uri).map_err(RecordError::UrlParse)?;
+    let mut title_ngrams_docids = HashMap::<_, RoaringTreemap>::new();
+    let mut content_ngrams_docids = HashMap::<_, RoaringTreemap>::new();
+
+    let (title, text) = match format {
+        RecordFormat::Warc => {
+            let http_body = record.body();
+            let head = ResponseHead::parse(http_body).map_err(RecordError::HttpParse)?;
+            if let Err(reason) = policy.check(head.status, head.content_type) {
+                return Ok(Some(IndexingOutput::skipped(reason)));
             }
+
+            let body = head.decode_body(&http_body[head.length..]);
+            let body = body.map_err(RecordError::BodyDecoding)?;
+
+            // The extractor only understands UTF-8
+            let html_body = decode_html(&body, head.content_type, &url);
+            let product = readability::extractor::extract(&mut html_body.as_bytes(), &url)
+                .map_err(RecordError::Extraction)?;
+            (product.title, product.text)
+        }
+        RecordFormat::Wet => {
+            let text = String::from_utf8_lossy(record.body()).into_owned();
+            (wet_title(&text).to_owned(), text)
+        }
+    };
+
+    // The WAT metadata refers to the WARC response record
+    let wat = record.header(WarcHeader::RecordID).and_then(|id| wat.get(&*id));
+    let title = match wat.and_then(|w| w.title.as_ref()) {
+        Some(wat_title) if title.trim().is_empty() => wat_title.clone(),
+        _ => title,
+    };
+
+    for trigram in TriGrams::new(cleanup_chars(title.chars())) {
+        title_ngrams_docids.entry(trigram).or_default().insert(record_id);
+    }
+
+    let mut simhasher = SimHasher::new();
+    for trigram in TriGrams::new(cleanup_chars(text.chars())) {
+        simhasher.add(trigram);
+        content_ngrams_docids.entry(trigram).or_default().insert(record_id);
+    }
+
+    Ok(Some(IndexingOutput {
+        title_ngrams_docids,
+        content_ngrams_docids,
+        documents: vec![(
+            record_id,
+            Document {
+                url,
+                metadata: DocumentMetadata {
+                    title: Some(title).filter(|t| !t.trim().is_empty()),
+                    lang: wat.and_then(|w| w.lang.clone()),
+                    description: wat.and_then(|w| w.description.clone()),
+                    date: record.header(WarcHeader::Date).map(Cow::into_owned),
+                    text_length: Some(text.chars().count() as u64),
+                    simhash: simhasher.finish(),
+                },
+                outlinks: wat.map(|w| w.outlinks.clone()).unwrap_or_default(),
+            },
+        )],
+        errors: RecordErrors::default(),
+        skipped: SkippedResponses::default(),
+    }))
+}
+
+/// Translates the record ids of the postings into the docids given to the
+/// documents, the records that are not in the `record_docids` are dropped.
+fn remap_postings(
+    postings: HashMap<[char; 3], RoaringTreemap>,
+    record_docids: &[(u64, u64)],
+) -> Vec<([char; 3], RoaringTreemap)> {
+    postings
+        .into_par_iter()
+        .map(|(trigram, record_ids)| {
+            // Both the record ids and the docids are given in ascending order
+            // therefore the docids we generate here are also sorted.
+            let docids = record_ids.iter().filter_map(|record_id| {
+                let index = record_docids.binary_search_by_key(&record_id, |(r, _)| *r).ok()?;
+                Some(record_docids[index].1)
+            });
+            (trigram, RoaringTreemap::from_sorted_iter(docids).unwrap())
+        })
+        .collect()
+}
+
+/// WET records don't have a title, we consider the first non-empty
+/// line of the text as the title when it is short enough.
+fn wet_title(text: &str) -> &str {
+    const MAX_TITLE_CHARS: usize = 120;
+    match text.lines().map(str::trim).find(|line| !line.is_empty()) {
+        Some(line) if line.chars().count() <= MAX_TITLE_CHARS => line,
+        _ => "",
+    }
+}
+
+/// The reasons why a single WARC record can fail to be indexed.
+#[derive(Debug)]
+enum RecordError {
+    Warc(warc::Error),
+    UrlParse(url::ParseError),
+    HttpParse(HttpError),
+    BodyDecoding(HttpError),
+    Extraction(readability::error::Error),
+}
+
+impl fmt::Display for RecordError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Warc(e) => write!(f, "invalid WARC record: {e}"),
+            Self::UrlParse(e) => write!(f, "invalid target URI: {e}"),
+            Self::HttpParse(e) => write!(f, "{e}"),
+            Self::BodyDecoding(e) => write!(f, "{e}"),
+            Self::Extraction(e) => write!(f, "content extraction failed: {e:?}"),
+        }
+    }
+}
+
+/// The number of records that failed to be indexed, by category.
+#[derive(Debug, Default, Clone, Copy)]
+struct RecordErrors {
+    warc: u64,
+    url_parse: u64,
+    http_parse: u64,
+    body_decoding: u64,
+    extraction: u64,
+}
+
+impl RecordErrors {
+    fn merge(self, other: Self) -> Self {
+        RecordErrors {
+            warc: self.warc + other.warc,
+            url_parse: self.url_parse + other.url_parse,
+            http_parse: self.http_parse + other.http_parse,
+            body_decoding: self.body_decoding + other.body_decoding,
+            extraction: self.extraction + other.extraction,
+        }
+    }
+}
+
+impl fmt::Display for RecordErrors {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let RecordErrors { warc, url_parse, http_parse, body_decoding, extraction } = self;
+        write!(
+            f,
+            "Skipped records: {warc} invalid WARC, {url_parse} invalid URL, \
+             {http_parse} invalid HTTP, {body_decoding} undecodable bodies, \
+             {extraction} failed extractions"
+        )
+    }
+}
+
+/// The number of responses that were not indexed, by reason.
+#[derive(Debug, Default)]
+struct SkippedResponses(BTreeMap<SkipReason, u64>);
+
+impl SkippedResponses {
+    fn merge(mut self, other: Self) -> Self {
+        for (reason, count) in other.0 {
+            *self.0.entry(reason).or_default() += count;
         }
+        self
     }
 }
 
+impl fmt::Display for SkippedResponses {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Skipped responses:")?;
+        if self.0.is_empty() {
+            return f.write_str(" none");
+        }
+        for (i, (reason, count)) in self.0.iter().enumerate() {
+            let separator = if i == 0 { " " } else { ", " };
+            write!(f, "{separator}{count} {reason}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Everything we store about an indexed document, except its postings.
+#[derive(Debug)]
+struct Document {
+    url: Url,
+    metadata: DocumentMetadata,
+    outlinks: Vec<Url>,
+}
+
 #[derive(Debug, Default)]
 struct IndexingOutput {
     title_ngrams_docids: HashMap<[char; 3], RoaringTreemap>,
     content_ngrams_docids: HashMap<[char; 3], RoaringTreemap>,
-    docids: RoaringTreemap,
-    urls: Vec<Url>,
+    /// The documents along with the record id used in the postings.
+    documents: Vec<(u64, Document)>,
+    errors: RecordErrors,
+    skipped: SkippedResponses,
 }
 
 impl IndexingOutput {
+    fn from_error(error: &RecordError) -> Self {
+        let mut errors = RecordErrors::default();
+        match error {
+            RecordError::Warc(_) => errors.warc += 1,
+            RecordError::UrlParse(_) => errors.url_parse += 1,
+            RecordError::HttpParse(_) => errors.http_parse += 1,
+            RecordError::BodyDecoding(_) => errors.body_decoding += 1,
+            RecordError::Extraction(_) => errors.extraction += 1,
+        }
+        IndexingOutput { errors, ..Default::default() }
+    }
+
+    fn skipped(reason: SkipReason) -> Self {
+        let skipped = SkippedResponses(BTreeMap::from([(reason, 1)]));
+        IndexingOutput { skipped, ..Default::default() }
+    }
+
     fn merge(mut self, other: Self) -> Self {
-        let IndexingOutput { title_ngrams_docids, content_ngrams_docids, docids, mut urls } = other;
+        let IndexingOutput {
+            title_ngrams_docids,
+            content_ngrams_docids,
+            mut documents,
+            errors,
+            skipped,
+        } = other;
 
         for (ngram, docids) in title_ngrams_docids {
             *self.title_ngrams_docids.entry(ngram).or_default() |= docids;
@@ -206,9 +1446,428 @@ impl IndexingOutput {
             *self.content_ngrams_docids.entry(ngram).or_default() |= docids;
         }
 
-        self.urls.append(&mut urls);
-        self.docids |= docids;
+        self.documents.append(&mut documents);
+        self.errors = self.errors.merge(errors);
+        self.skipped = self.skipped.merge(skipped);
 
         self
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use croissantine::task::Priority;
+
+    use super::*;
+
+    /// Formats a WARC record, the body is written as is.
+    fn record(warc_type: &str, id: u32, uri: &str, date: &str, body: &str) -> String {
+        format!(
+            "WARC/1.0\r\nWARC-Type: {warc_type}\r\nWARC-Target-URI: {uri}\r\nWARC-Date: {date}\r\n\
+             WARC-Record-ID: <urn:uuid:{id}>\r\nContent-Length: {}\r\n\r\n{body}\r\n\r\n",
+            body.len()
+        )
+    }
+
+    fn http_response(status: &str, content_type: &str, body: &str) -> String {
+        format!("HTTP/1.1 {status}\r\nContent-Type: {content_type}\r\n\r\n{body}")
+    }
+
+    fn settings(duplicates: DuplicatePolicy) -> Settings {
+        Settings {
+            with_wat: false,
+            policy: InclusionPolicy::default(),
+            duplicates,
+            jobs: 1,
+            poll_interval: Duration::from_secs(1),
+            data_source: DataSource::default(),
+        }
+    }
+
+    fn open_database(dir: &Path) -> Database {
+        let mut options = EnvOpenOptions::new();
+        options.map_size(10 * 1024 * 1024);
+        Database::open_or_create(options, dir).unwrap()
+    }
+
+    /// Extracts the documents of a local file and writes them, returns the number written.
+    fn index_file(database: &Database, path: &Path, duplicates: DuplicatePolicy) -> u64 {
+        let task = Task::new(Priority::Normal, TaskKind::LocalWarc(path.to_owned()));
+        let output = extract_task(&task, &settings(duplicates), &Progress::new()).unwrap();
+        let mut wtxn = database.write_txn().unwrap();
+        let written = write_output(database, &mut wtxn, output, duplicates).unwrap();
+        wtxn.commit().unwrap();
+        written
+    }
+
+    #[test]
+    fn local_warc_records() {
+        let dir = tempfile::tempdir().unwrap();
+        let date = "2023-06-01T10:00:00Z";
+        let html = "<html><head><title>Croissant recipe</title></head>\
+                    <body><p>Butter, flour and patience make good croissants.</p></body></html>";
+        let page = http_response("200 OK", "text/html; charset=utf-8", html);
+        let missing = http_response("404 Not Found", "text/html", html);
+        let image = http_response("200 OK", "image/png", "PNG");
+        let records = [
+            record("warcinfo", 0, "https://a.fr/", date, "software: test"),
+            record("response", 1, "https://a.fr/croissant", date, &page),
+            record("response", 2, "not a url", date, &page),
+            record("response", 3, "https://a.fr/garbage", date, "this is not HTTP\r\n\r\n"),
+            record("response", 4, "https://a.fr/missing", date, &missing),
+            record("response", 5, "https://a.fr/logo.png", date, &image),
+            record("response", 6, "https://a.fr/dated", "yesterday", &page),
+        ];
+        let path = dir.path().join("records.warc");
+        fs::write(&path, records.concat()).unwrap();
+
+        let task = Task::new(Priority::Normal, TaskKind::LocalWarc(path.clone()));
+        let progress = Progress::new();
+        let output = extract_task(&task, &settings(DuplicatePolicy::Newest), &progress).unwrap();
+
+        // Every failed or skipped record is counted, the others are still extracted
+        let RecordErrors { warc, url_parse, http_parse, body_decoding, extraction } = output.errors;
+        assert_eq!((warc, url_parse, http_parse, body_decoding, extraction), (1, 1, 1, 0, 0));
+        let skipped = BTreeMap::from([
+            (SkipReason::Status(404), 1),
+            (SkipReason::ContentType(String::from("image/png")), 1),
+        ]);
+        assert_eq!(output.skipped.0, skipped);
+        assert_eq!(output.documents.len(), 1);
+
+        let progress = progress.finish();
+        assert_eq!(progress.records_processed, records.len() as u64);
+        assert_eq!(progress.bytes_downloaded, fs::metadata(&path).unwrap().len());
+        assert_eq!(progress.stage, None);
+
+        let database = open_database(dir.path());
+        let mut wtxn = database.write_txn().unwrap();
+        assert_eq!(write_output(&database, &mut wtxn, output, DuplicatePolicy::Newest).unwrap(), 1);
+        wtxn.commit().unwrap();
+
+        let rtxn = database.read_txn().unwrap();
+        let docid = database.uri_docid(&rtxn, "https://a.fr/croissant").unwrap();
+        assert_eq!(docid, Some(0));
+        assert_eq!(database.all_docids(&rtxn).unwrap(), RoaringTreemap::from_iter([0]));
+        let metadata = database.docid_metadata.get(&rtxn, &0).unwrap().unwrap();
+        assert_eq!(metadata.title.as_deref(), Some("Croissant recipe"));
+        assert_eq!(metadata.date.as_deref(), Some(date));
+        let mut key = String::new();
+        for trigram in TriGrams::new(cleanup_chars("Croissant recipe".chars())) {
+            let key = encode_trigram(&mut key, trigram);
+            let postings = database.title_ngrams_docids.get(&rtxn, key).unwrap().unwrap();
+            assert!(postings.contains(0), "{key:?} is missing the document");
+        }
+    }
+
+    #[test]
+    fn wet_files_and_duplicate_urls() {
+        let dir = tempfile::tempdir().unwrap();
+        let database = open_database(dir.path());
+        let write = |name: &str, records: &[String]| {
+            let path = dir.path().join(name);
+            fs::write(&path, records.concat()).unwrap();
+            path
+        };
+        let (january, june) = ("2023-01-01T00:00:00Z", "2023-06-01T00:00:00Z");
+
+        let first = write(
+            "first.wet",
+            &[
+                record("conversion", 1, "https://a.fr/", january, "Croissant au beurre\nDe Paris."),
+                record("conversion", 2, "https://b.fr/", january, "Baguette tradition\nDe Lyon."),
+                // Only the conversion records of a WET file are indexed
+                record("metadata", 3, "https://c.fr/", january, "Not indexed"),
+            ],
+        );
+        assert_eq!(index_file(&database, &first, DuplicatePolicy::Newest), 2);
+
+        let rtxn = database.read_txn().unwrap();
+        let docid_a = database.uri_docid(&rtxn, "https://a.fr/").unwrap().unwrap();
+        let metadata = database.docid_metadata.get(&rtxn, &docid_a).unwrap().unwrap();
+        assert_eq!(metadata.title.as_deref(), Some("Croissant au beurre"));
+        assert!(database.uri_docid(&rtxn, "https://c.fr/").unwrap().is_none());
+        let all_docids = database.all_docids(&rtxn).unwrap();
+        drop(rtxn);
+
+        // Indexing the same file again doesn't replace anything
+        assert_eq!(index_file(&database, &first, DuplicatePolicy::Newest), 0);
+        assert_eq!(index_file(&database, &first, DuplicatePolicy::Longest), 0);
+        let rtxn = database.read_txn().unwrap();
+        assert_eq!(database.uri_docid(&rtxn, "https://a.fr/").unwrap(), Some(docid_a));
+        assert_eq!(database.all_docids(&rtxn).unwrap(), all_docids);
+        assert!(database.deleted_docids(&rtxn).unwrap().is_empty());
+        drop(rtxn);
+
+        // A newer version of a URL replaces the indexed one
+        let newer = "Pain au chocolat\nAussi au beurre.";
+        let newer = write("newer.wet", &[record("conversion", 4, "https://a.fr/", june, newer)]);
+        assert_eq!(index_file(&database, &newer, DuplicatePolicy::Newest), 1);
+
+        let rtxn = database.read_txn().unwrap();
+        let new_docid_a = database.uri_docid(&rtxn, "https://a.fr/").unwrap().unwrap();
+        assert_ne!(new_docid_a, docid_a);
+        assert_eq!(database.docid_uri.get(&rtxn, &new_docid_a).unwrap(), Some("https://a.fr/"));
+        assert_eq!(database.docid_uri.get(&rtxn, &docid_a).unwrap(), None);
+        let all_docids = database.all_docids(&rtxn).unwrap();
+        assert!(all_docids.contains(new_docid_a) && !all_docids.contains(docid_a));
+        assert_eq!(all_docids.len(), 2);
+        let metadata = database.docid_metadata.get(&rtxn, &new_docid_a).unwrap().unwrap();
+        assert_eq!(metadata.title.as_deref(), Some("Pain au chocolat"));
+        drop(rtxn);
+
+        // An older version is ignored
+        let older = "Chausson aux pommes\nSans beurre.";
+        let older = write("older.wet", &[record("conversion", 5, "https://a.fr/", january, older)]);
+        assert_eq!(index_file(&database, &older, DuplicatePolicy::Newest), 0);
+        let rtxn = database.read_txn().unwrap();
+        assert_eq!(database.uri_docid(&rtxn, "https://a.fr/").unwrap(), Some(new_docid_a));
+    }
+
+    #[test]
+    fn deleted_documents_are_purged_in_bulk() {
+        let dir = tempfile::tempdir().unwrap();
+        let database = open_database(dir.path());
+        let (january, june) = ("2023-01-01T00:00:00Z", "2023-06-01T00:00:00Z");
+        let records: Vec<_> = (0..20)
+            .map(|i| {
+                let text = format!("Croissant {i}\nRecette numéro {i}.");
+                record("conversion", i, &format!("https://a.fr/{i}"), january, &text)
+            })
+            .collect();
+        let path = dir.path().join("first.wet");
+        fs::write(&path, records.concat()).unwrap();
+        assert_eq!(index_file(&database, &path, DuplicatePolicy::Newest), 20);
+
+        let postings = |database: &Database| -> BTreeMap<String, RoaringTreemap> {
+            let rtxn = database.read_txn().unwrap();
+            let iter = database.content_ngrams_docids.iter(&rtxn).unwrap();
+            iter.map(|r| r.map(|(trigram, bitmap)| (trigram.to_owned(), bitmap)).unwrap()).collect()
+        };
+        let before = postings(&database);
+        let rtxn = database.read_txn().unwrap();
+        let old_docid = database.uri_docid(&rtxn, "https://a.fr/0").unwrap().unwrap();
+        drop(rtxn);
+
+        // A replaced document stays in the postings, none of them is rewritten
+        let newer = record("conversion", 20, "https://a.fr/0", june, "Chocolatine");
+        fs::write(&path, newer).unwrap();
+        assert_eq!(index_file(&database, &path, DuplicatePolicy::Newest), 1);
+        purge_deleted_documents(&database).unwrap();
+        let rtxn = database.read_txn().unwrap();
+        let new_docid = database.uri_docid(&rtxn, "https://a.fr/0").unwrap().unwrap();
+        assert_eq!(database.deleted_docids(&rtxn).unwrap(), RoaringTreemap::from_iter([old_docid]));
+        drop(rtxn);
+        for (trigram, bitmap) in postings(&database) {
+            match before.get(&trigram) {
+                Some(before) => {
+                    let added = before | &RoaringTreemap::from_iter([new_docid]);
+                    assert!(bitmap == *before || bitmap == added, "{trigram:?} was rewritten");
+                }
+                None => assert_eq!(bitmap, RoaringTreemap::from_iter([new_docid])),
+            }
+        }
+
+        // They are purged once they are a significant share of the documents
+        let mut wtxn = database.write_txn().unwrap();
+        let deleted: RoaringTreemap = ["https://a.fr/1", "https://a.fr/2"]
+            .iter()
+            .map(|url| database.uri_docid(&wtxn, url).unwrap().unwrap())
+            .collect();
+        database.delete_documents(&mut wtxn, &deleted).unwrap();
+        wtxn.commit().unwrap();
+        purge_deleted_documents(&database).unwrap();
+        let rtxn = database.read_txn().unwrap();
+        assert!(database.deleted_docids(&rtxn).unwrap().is_empty());
+        drop(rtxn);
+        let deleted = deleted | RoaringTreemap::from_iter([old_docid]);
+        assert!(postings(&database).values().all(|bitmap| bitmap.is_disjoint(&deleted)));
+    }
+
+    #[test]
+    fn reindex_after_compaction() {
+        let dir = tempfile::tempdir().unwrap();
+        let database = open_database(dir.path());
+        let date = "2023-01-01T00:00:00Z";
+        let records = [
+            record("conversion", 1, "https://a.fr/", date, "Croissant au beurre"),
+            record("conversion", 2, "https://b.fr/", date, "Baguette tradition"),
+            record("conversion", 3, "https://c.fr/", date, "Pain au chocolat"),
+        ];
+        let path = dir.path().join("records.wet");
+        fs::write(&path, records.concat()).unwrap();
+        assert_eq!(index_file(&database, &path, DuplicatePolicy::Newest), 3);
+
+        let mut wtxn = database.write_txn().unwrap();
+        let docid_a = database.uri_docid(&wtxn, "https://a.fr/").unwrap().unwrap();
+        database.delete_documents(&mut wtxn, &RoaringTreemap::from_iter([docid_a])).unwrap();
+        // A crashed write left a document that isn't indexed
+        database.docid_uri.put(&mut wtxn, &3, "https://d.fr/").unwrap();
+        database.put_uri_docid(&mut wtxn, "https://d.fr/", 3).unwrap();
+        database.docid_metadata.put(&mut wtxn, &3, &DocumentMetadata::default()).unwrap();
+        assert_eq!(database.compact_docids(&mut wtxn).unwrap(), Some(2));
+        assert_eq!(database.uri_docid(&wtxn, "https://d.fr/").unwrap(), None);
+        wtxn.commit().unwrap();
+        check_consistency(&database, 10, Some(0)).unwrap();
+
+        // The deleted URL and the one of the unknown docid are indexed as new documents
+        let records = [
+            record("conversion", 4, "https://a.fr/", date, "Croissant aux amandes"),
+            record("conversion", 5, "https://d.fr/", date, "Chausson aux pommes"),
+        ];
+        fs::write(&path, records.concat()).unwrap();
+        assert_eq!(index_file(&database, &path, DuplicatePolicy::Newest), 2);
+        check_consistency(&database, 10, Some(0)).unwrap();
+
+        let rtxn = database.read_txn().unwrap();
+        assert_eq!(database.all_docids(&rtxn).unwrap(), RoaringTreemap::from_iter(0..4));
+        for url in ["https://a.fr/", "https://b.fr/", "https://c.fr/", "https://d.fr/"] {
+            let docid = database.uri_docid(&rtxn, url).unwrap().unwrap();
+            assert_eq!(database.docid_uri.get(&rtxn, &docid).unwrap(), Some(url));
+        }
+    }
+
+    #[test]
+    fn long_urls() {
+        let dir = tempfile::tempdir().unwrap();
+        let database = open_database(dir.path());
+        // LMDB refuses the keys longer than 511 bytes
+        let url = format!("https://a.fr/{}", "croissant/".repeat(60));
+        let date = "2023-01-01T00:00:00Z";
+        let path = dir.path().join("records.wet");
+        fs::write(&path, record("conversion", 1, &url, date, "Croissant au beurre")).unwrap();
+        assert_eq!(index_file(&database, &path, DuplicatePolicy::Newest), 1);
+        check_consistency(&database, 10, Some(0)).unwrap();
+
+        let mut wtxn = database.write_txn().unwrap();
+        let docid = database.uri_docid(&wtxn, &url).unwrap().unwrap();
+        assert_eq!(database.docid_uri.get(&wtxn, &docid).unwrap(), Some(url.as_str()));
+        database.rebuild_uri_docid(&mut wtxn).unwrap();
+        assert_eq!(database.uri_docid(&wtxn, &url).unwrap(), Some(docid));
+
+        database.delete_documents(&mut wtxn, &RoaringTreemap::from_iter([docid])).unwrap();
+        assert_eq!(database.uri_docid(&wtxn, &url).unwrap(), None);
+        assert!(database.uri_digest_docid.is_empty(&wtxn).unwrap());
+        assert!(database.docid_uri.is_empty(&wtxn).unwrap());
+    }
+
+    #[test]
+    fn consistency_check() {
+        let dir = tempfile::tempdir().unwrap();
+        let database = open_database(dir.path());
+        let date = "2023-01-01T00:00:00Z";
+        let records = [
+            record("conversion", 1, "https://a.fr/", date, "Croissant au beurre\nDe Paris."),
+            record("conversion", 2, "https://b.fr/", date, "Baguette tradition\nDe Lyon."),
+        ];
+        let path = dir.path().join("records.wet");
+        fs::write(&path, records.concat()).unwrap();
+        assert_eq!(index_file(&database, &path, DuplicatePolicy::Newest), 2);
+        check_consistency(&database, 10, Some(0)).unwrap();
+
+        // Every URL resolves back to its own document
+        let rtxn = database.read_txn().unwrap();
+        let all_docids = database.all_docids(&rtxn).unwrap();
+        assert_eq!(all_docids.len(), 2);
+        for docid in &all_docids {
+            let uri = database.docid_uri.get(&rtxn, &docid).unwrap().unwrap();
+            assert_eq!(database.uri_docid(&rtxn, uri).unwrap(), Some(docid));
+        }
+        let docid_a = database.uri_docid(&rtxn, "https://a.fr/").unwrap().unwrap();
+        let docid_b = database.uri_docid(&rtxn, "https://b.fr/").unwrap().unwrap();
+        drop(rtxn);
+
+        // A URL resolving to another document is reported
+        let mut wtxn = database.write_txn().unwrap();
+        database.put_uri_docid(&mut wtxn, "https://a.fr/", docid_b).unwrap();
+        wtxn.commit().unwrap();
+        assert!(check_consistency(&database, 10, Some(0)).is_err());
+
+        // As are the documents missing from the content postings
+        let mut wtxn = database.write_txn().unwrap();
+        database.put_uri_docid(&mut wtxn, "https://a.fr/", docid_a).unwrap();
+        let postings: Vec<(String, RoaringTreemap)> = database
+            .content_ngrams_docids
+            .iter(&wtxn)
+            .unwrap()
+            .map(|result| result.map(|(trigram, bitmap)| (trigram.to_owned(), bitmap)).unwrap())
+            .collect();
+        for (trigram, mut bitmap) in postings {
+            bitmap.remove(docid_a);
+            database.content_ngrams_docids.put(&mut wtxn, &trigram, &bitmap).unwrap();
+        }
+        wtxn.commit().unwrap();
+        assert!(check_consistency(&database, 10, Some(0)).is_err());
+    }
+
+    #[test]
+    fn failed_tasks_are_recorded() {
+        let dir = tempfile::tempdir().unwrap();
+        let database = open_database(dir.path());
+        let date = "2023-01-01T00:00:00Z";
+        let path = dir.path().join("records.wet");
+        fs::write(&path, record("conversion", 1, "https://a.fr/", date, "Croissant")).unwrap();
+
+        let mut wtxn = database.write_txn().unwrap();
+        for path in [dir.path().join("missing.wet"), path] {
+            let task = Task::new(Priority::Normal, TaskKind::LocalWarc(path));
+            database.enqueue_task(&mut wtxn, &task).unwrap();
+        }
+        let settings = Settings { jobs: 2, ..settings(DuplicatePolicy::Newest) };
+        let batch = claim_batch(&database, &mut wtxn, settings.jobs).unwrap();
+        wtxn.commit().unwrap();
+        assert_eq!(batch.len(), 2);
+
+        // The failed task doesn't prevent the other one from being indexed
+        index_batch(&database, batch, &settings).unwrap();
+        let rtxn = database.read_txn().unwrap();
+        assert!(database.processing.is_empty(&rtxn).unwrap());
+        assert_eq!(database.all_docids(&rtxn).unwrap().len(), 1);
+        let reports: Vec<_> = database.history.iter(&rtxn).unwrap().map(|r| r.unwrap().1).collect();
+        assert_eq!(reports.len(), 2);
+        assert!(reports[0].task.to_string().ends_with("missing.wet"));
+        assert!(reports[0].error.as_ref().is_some_and(|e| e.contains("No such file")));
+        assert_eq!(reports[1].error, None);
+        assert_eq!(reports[1].progress.documents_indexed, 1);
+    }
+
+    #[test]
+    fn single_indexer() {
+        let dir = tempfile::tempdir().unwrap();
+        let lock = lock_indexer(dir.path()).unwrap();
+        assert!(lock_indexer(dir.path()).is_err());
+        drop(lock);
+        assert!(lock_indexer(dir.path()).is_ok());
+    }
+
+    #[test]
+    fn tasks_that_cannot_be_written_fail() {
+        let dir = tempfile::tempdir().unwrap();
+        let database = open_database(dir.path());
+        let mut wtxn = database.write_txn().unwrap();
+        let task = Task::new(Priority::Normal, TaskKind::LocalWarc(dir.path().join("a.warc")));
+        let key = database.enqueue_task(&mut wtxn, &task).unwrap();
+        let task = database.claim_task(&mut wtxn, key).unwrap().unwrap();
+        wtxn.commit().unwrap();
+
+        // The same record id is given to two documents
+        let document = |url| Document {
+            url: Url::parse(url).unwrap(),
+            metadata: DocumentMetadata::default(),
+            outlinks: Vec::new(),
+        };
+        let documents = vec![(0, document("https://a.fr/")), (0, document("https://b.fr/"))];
+        let output = IndexingOutput { documents, ..Default::default() };
+        let settings = settings(DuplicatePolicy::Newest);
+        finish_indexing_task(&database, key, task, Ok(output), &Progress::new(), &settings)
+            .unwrap();
+
+        // Nothing was written but the task is no longer processed
+        let rtxn = database.read_txn().unwrap();
+        assert!(database.processing.is_empty(&rtxn).unwrap());
+        assert!(database.all_docids(&rtxn).unwrap().is_empty());
+        assert!(database.uri_digest_docid.is_empty(&rtxn).unwrap());
+        let (_, report) = database.history.last(&rtxn).unwrap().unwrap();
+        assert_eq!(report.error.as_deref(), Some("the same record id was given to two documents"));
+    }
+}
@@ -1,20 +1,35 @@
 use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::ops::Range;
 use std::path::PathBuf;
-use std::sync::Arc;
-use std::time::Instant;
-use std::{fs, include_bytes};
+use std::str::FromStr;
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::{Duration, Instant};
+use std::{env, fs, include_bytes, iter, thread};
 
+use anyhow::Context;
 use askama::Template;
-use axum::extract::{Query, State};
-use axum::http::header;
-use axum::response::{IntoResponse, Redirect};
+use axum::extract::{ConnectInfo, Query, State};
+use axum::http::{header, HeaderMap, Request, StatusCode};
+use axum::middleware::{self, Next};
+use axum::response::{IntoResponse, Redirect, Response};
 use axum::routing::{get, post};
-use axum::{Form, Router};
+use axum::{Extension, Form, Router};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
 use clap::Parser;
+use croissantine::auth::{Credentials, CsrfKey, FailedLogins, SessionKey, UrlSigner};
+use croissantine::clicks::{Click, SearchLog, Sessions};
 use croissantine::database::Database;
-use croissantine::task::Task;
+use croissantine::progress::{unix_timestamp, TaskProgress};
+use croissantine::source::DataSource;
+use croissantine::task::{
+    is_crawl_id, PathsSelection, Priority, Sample, Task, TaskKey, TaskKind, WarcPaths,
+};
 use croissantine::text::cleanup_chars;
+use croissantine::text::simhash::{hamming_distance, NEAR_DUPLICATE_DISTANCE};
 use croissantine::text::trigrams::TriGrams;
+use croissantine::wakeup;
 use croissantine::{encode_trigram, DATABASE_MAX_SIZE};
 use heed::EnvOpenOptions;
 use roaring::MultiOps;
@@ -31,28 +46,172 @@ struct Options {
     /// The database path where the indexed data is stored.
     #[arg(long, default_value = "croissantine.db")]
     database_path: PathBuf,
+
+    /// The base URL of the crawl files, a mirror served over HTTP or a local
+    /// copy like `file:///data/commoncrawl/`, the indexer must be able to read it.
+    #[arg(long, default_value = DataSource::COMMON_CRAWL)]
+    data_source: DataSource,
+
+    /// A file of `name:hash` lines, the admins allowed to use the indexer page,
+    /// the hashes are given by `indexer hash-password`. The same lines can also
+    /// be given in the `CROISSANTINE_ADMIN_CREDENTIALS` environment variable.
+    #[arg(long)]
+    admin_credentials: Option<PathBuf>,
 }
 
+/// The environment variable containing admin credentials, like the file.
+const ADMIN_CREDENTIALS_ENV: &str = "CROISSANTINE_ADMIN_CREDENTIALS";
+
 struct AppState {
     database: Database,
+    /// Used to wake the indexer up when a task is enqueued.
+    database_path: PathBuf,
+    /// Where the crawls registered on the indexer page are fetched from.
+    data_source: DataSource,
+    /// The admins allowed to use the admin routes with a password.
+    credentials: Credentials,
+    csrf_key: CsrfKey,
+    /// Signs the sessions of the admins, the passwords are slow to check.
+    session_key: SessionKey,
+    failed_logins: FailedLogins,
+    /// Signs the links of the results, the key is stored in the database.
+    url_signer: UrlSigner,
+    /// Identifies the users in the logged searches and clicks without tracking them.
+    sessions: Sessions,
+    /// The reserved ids not yet given to a search, the links of the results refer to them.
+    search_ids: Mutex<Range<u64>>,
+    /// The searches and clicks to write in the database, see [`write_logs`].
+    logs: mpsc::SyncSender<LogEvent>,
+}
+
+impl AppState {
+    /// Gives an id to a new search, a block of ids is reserved in the
+    /// database when the ones reserved before are all given.
+    fn next_search_id(&self) -> heed::Result<u64> {
+        let mut ids = self.search_ids.lock().unwrap();
+        if ids.is_empty() {
+            // This is rare but it waits for the write transaction
+            *ids = tokio::task::block_in_place(|| {
+                let mut wtxn = self.database.write_txn()?;
+                let ids = self.database.reserve_search_ids(&mut wtxn, SEARCH_IDS_BLOCK)?;
+                wtxn.commit().map(|()| ids)
+            })?;
+        }
+        Ok(ids.next().unwrap())
+    }
+
+    /// Sends an event to be logged without waiting for it to be written.
+    fn log(&self, event: LogEvent) {
+        if let Err(e) = self.logs.try_send(event) {
+            eprintln!("Dropping a search log: {e}");
+        }
+    }
+}
+
+/// A search or a click to write in the logs.
+enum LogEvent {
+    Search(u64, SearchLog),
+    Click(Click),
+}
+
+/// The number of search ids reserved at once.
+const SEARCH_IDS_BLOCK: u64 = 1000;
+
+/// The number of events waiting to be written, the next ones are dropped.
+const LOG_QUEUE_SIZE: usize = 10_000;
+
+/// How long the events are gathered before being written in a single transaction.
+const LOG_BATCH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Writes the logged searches and clicks in batches, the searches
+/// never wait for the write transaction of the database.
+fn write_logs(database: &Database, events: mpsc::Receiver<LogEvent>) {
+    while let Ok(event) = events.recv() {
+        thread::sleep(LOG_BATCH_INTERVAL);
+        let batch: Vec<_> = iter::once(event).chain(events.try_iter()).collect();
+        let count = batch.len();
+        let result = database.write_txn().and_then(|mut wtxn| {
+            for event in batch {
+                match event {
+                    LogEvent::Search(id, search) => database.log_search(&mut wtxn, id, &search)?,
+                    LogEvent::Click(click) => {
+                        database.log_click(&mut wtxn, click)?;
+                    }
+                }
+            }
+            wtxn.commit()
+        });
+        if let Err(e) = result {
+            eprintln!("Failed to write {count} search logs: {e}");
+        }
+    }
 }
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    let Options { listen, database_path } = Options::parse();
+    let Options { listen, database_path, data_source, admin_credentials } = Options::parse();
+
+    let mut credentials = Credentials::default();
+    if let Some(path) = admin_credentials {
+        let text = fs::read_to_string(&path)
+            .with_context(|| format!("reading the admin credentials {}", path.display()))?;
+        let file_credentials = Credentials::parse(&text)
+            .with_context(|| format!("invalid admin credentials in {}", path.display()))?;
+        credentials.extend(file_credentials);
+    }
+    if let Ok(text) = env::var(ADMIN_CREDENTIALS_ENV) {
+        let env_credentials = Credentials::parse(&text)
+            .with_context(|| format!("invalid admin credentials in {ADMIN_CREDENTIALS_ENV}"))?;
+        credentials.extend(env_credentials);
+    }
+    if credentials.is_empty() {
+        eprintln!("No admin credentials given, the admin routes only accept API tokens");
+    }
 
     let mut options = EnvOpenOptions::new();
     options.map_size(DATABASE_MAX_SIZE);
     fs::create_dir_all(&database_path)?;
-    let database = Database::open_or_create(options, database_path)?;
-    let app_state = Arc::new(AppState { database });
+    let database = Database::open_or_create(options, &database_path)?;
+    let csrf_key = CsrfKey::generate();
+    let mut wtxn = database.write_txn()?;
+    let url_signer = UrlSigner::new(&database.secret(&mut wtxn, "redirect")?);
+    let sessions = Sessions::new(&database.secret(&mut wtxn, "sessions")?);
+    let search_ids = Mutex::new(database.reserve_search_ids(&mut wtxn, SEARCH_IDS_BLOCK)?);
+    wtxn.commit()?;
+    let (logs, events) = mpsc::sync_channel(LOG_QUEUE_SIZE);
+    let app_state = Arc::new(AppState {
+        database,
+        database_path,
+        data_source,
+        credentials,
+        csrf_key,
+        session_key: SessionKey::generate(),
+        failed_logins: FailedLogins::default(),
+        url_signer,
+        sessions,
+        search_ids,
+        logs,
+    });
+    let logs_state = Arc::clone(&app_state);
+    thread::spawn(move || write_logs(&logs_state.database, events));
+
+    // the routes that enqueue tasks and control the indexer
+    let admin = Router::new()
+        .route("/indexer", get(indexer))
+        .route("/register-warc", post(register_warc))
+        .route("/delete-documents", post(delete_documents))
+        .route("/cancel-task", post(cancel_task))
+        .route("/move-task-to-front", post(move_task_to_front))
+        .route("/clear-crawl", post(clear_crawl))
+        .route("/pause-indexer", post(pause_indexer))
+        .route("/resume-indexer", post(resume_indexer))
+        .route_layer(middleware::from_fn_with_state(Arc::clone(&app_state), require_admin));
 
     // our router
     let app = Router::new()
         .route("/", get(welcome))
         .route("/search", get(search))
-        .route("/indexer", get(indexer))
-        .route("/register-warc", post(register_warc))
+        .merge(admin)
         .route("/about", get(about))
         .route("/redirect", get(redirect))
         .route("/assets/images/croissantine-logo.svg", get(assets_images_logo))
@@ -60,11 +219,151 @@ async fn main() -> anyhow::Result<()> {
 
     // run it with hyper on localhost:3000
     let addr = listen.parse().unwrap();
-    axum::Server::bind(&addr).serve(app.into_make_service()).await?;
+    // the address of the clients identifies their session
+    let service = app.into_make_service_with_connect_info::<SocketAddr>();
+    axum::Server::bind(&addr).serve(service).await?;
 
     Ok(())
 }
 
+/// How an admin authenticated on the admin routes.
+#[derive(Clone)]
+enum Admin {
+    /// With a password, the browsers send it with every request.
+    Password(String),
+    /// With an API token, scripts send it explicitly.
+    Token(String),
+}
+
+impl Admin {
+    fn name(&self) -> &str {
+        match self {
+            Admin::Password(name) | Admin::Token(name) => name,
+        }
+    }
+}
+
+/// The cookie of the session of an admin logged in with a password.
+const SESSION_COOKIE: &str = "croissantine-session";
+
+/// Only lets the admins through, authenticated with a password
+/// (`Basic` authorization) or with an API token (`Bearer` authorization).
+async fn require_admin<B>(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(address): ConnectInfo<SocketAddr>,
+    mut request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    match authenticate(&state, address, request.headers()).await {
+        Ok((admin, session)) => {
+            request.extensions_mut().insert(admin);
+            let mut response = next.run(request).await;
+            // The password is only checked once, the session is used by the next requests
+            if let Some(session) = session {
+                let cookie = format!(
+                    "{SESSION_COOKIE}={session}; Path=/; HttpOnly; SameSite=Strict; Max-Age=43200"
+                );
+                if let Ok(cookie) = cookie.parse() {
+                    response.headers_mut().insert(header::SET_COOKIE, cookie);
+                }
+            }
+            response
+        }
+        Err(StatusCode::TOO_MANY_REQUESTS) => {
+            let error = "Too many failed logins, try again in a minute";
+            (StatusCode::TOO_MANY_REQUESTS, error).into_response()
+        }
+        Err(status) => {
+            let challenge = [(header::WWW_AUTHENTICATE, r#"Basic realm="croissantine""#)];
+            (status, challenge).into_response()
+        }
+    }
+}
+
+/// Returns the admin and the new session to give it, if it logged in with its password.
+async fn authenticate(
+    state: &Arc<AppState>,
+    address: SocketAddr,
+    headers: &HeaderMap,
+) -> std::result::Result<(Admin, Option<String>), StatusCode> {
+    let authorization = headers.get(header::AUTHORIZATION).and_then(|v| v.to_str().ok());
+    if let Some(token) = authorization.and_then(|a| a.strip_prefix("Bearer ")) {
+        let rtxn = state.database.read_txn().map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        let api_token = state.database.find_api_token(&rtxn, token.trim());
+        let api_token = api_token.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        return match api_token {
+            Some(api_token) => Ok((Admin::Token(api_token.name), None)),
+            None => Err(StatusCode::UNAUTHORIZED),
+        };
+    }
+
+    // The admins removed from the credentials lose their sessions
+    let session = cookie(headers, SESSION_COOKIE).and_then(|s| state.session_key.verify(s));
+    if let Some(name) = session.filter(|name| state.credentials.contains(name)) {
+        return Ok((Admin::Password(name), None));
+    }
+
+    let encoded = authorization.and_then(|a| a.strip_prefix("Basic "));
+    let decoded = encoded.and_then(|encoded| STANDARD.decode(encoded.trim()).ok());
+    let Some(decoded) = decoded.and_then(|decoded| String::from_utf8(decoded).ok()) else {
+        return Err(StatusCode::UNAUTHORIZED);
+    };
+    let Some((name, password)) = decoded.split_once(':') else {
+        return Err(StatusCode::UNAUTHORIZED);
+    };
+
+    let client = address.ip();
+    if state.failed_logins.is_blocked(client) {
+        return Err(StatusCode::TOO_MANY_REQUESTS);
+    }
+    // Hashing the password takes a while, it must not block the other requests
+    let (name, password) = (name.to_owned(), password.to_owned());
+    let verifier = Arc::clone(state);
+    let verified = tokio::task::spawn_blocking(move || {
+        verifier.credentials.verify(&name, &password).then_some(name)
+    })
+    .await;
+    match verified {
+        Ok(Some(name)) => {
+            state.failed_logins.succeeded(client);
+            let session = state.session_key.session(&name);
+            Ok((Admin::Password(name), Some(session)))
+        }
+        Ok(None) => {
+            eprintln!("Failed login from {client}");
+            state.failed_logins.failed(client);
+            Err(StatusCode::UNAUTHORIZED)
+        }
+        Err(_) => Err(StatusCode::INTERNAL_SERVER_ERROR),
+    }
+}
+
+/// Returns the value of a cookie sent by the browser.
+fn cookie<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .filter_map(|cookie| cookie.trim().split_once('='))
+        .find_map(|(cookie, value)| (cookie == name).then_some(value))
+}
+
+/// The browsers send the password of an admin with every request, even the ones
+/// made by the forms of other websites, our forms must carry a CSRF token.
+fn check_csrf(state: &AppState, admin: &Admin, token: &str) -> FormResponse<()> {
+    match admin {
+        Admin::Token(_) => Ok(()),
+        Admin::Password(name) if state.csrf_key.verify(name, token) => Ok(()),
+        Admin::Password(_) => {
+            Err((StatusCode::FORBIDDEN, String::from("The form expired, reload the page")))
+        }
+    }
+}
+
+/// The result of the admin forms, they redirect to the indexer page.
+type FormResponse<T = Redirect> = std::result::Result<T, (StatusCode, String)>;
+
 #[derive(Template)]
 #[template(path = "welcome.html")]
 struct WelcomeTemplate {
@@ -89,10 +388,21 @@ struct ResultsTemplate {
 struct Result {
     link: String,
     title: String,
+    /// The number of near-duplicates of this result that were collapsed.
+    similar: usize,
 }
 
+/// The maximum number of results displayed.
+const MAX_RESULTS: usize = 20;
+
+/// The maximum number of documents fetched to display the results,
+/// near-duplicates are collapsed so we need more documents than results.
+const MAX_CANDIDATES: usize = 200;
+
 async fn search(
     State(state): State<Arc<AppState>>,
+    ConnectInfo(address): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
     Query(params): Query<HashMap<String, String>>,
 ) -> impl IntoResponse {
     let database = &state.database;
@@ -101,6 +411,20 @@ async fn search(
         _ => return Box::new(Redirect::temporary("/")).into_response(),
     };
 
+    // The links of the results refer to the search to log the clicks
+    let session = session_id(&state, address, &headers);
+    let search = SearchLog { query: query.clone(), session, timestamp: unix_timestamp() };
+    let search_id = match state.next_search_id() {
+        Ok(id) => {
+            state.log(LogEvent::Search(id, search));
+            Some(id)
+        }
+        Err(e) => {
+            eprintln!("Not logging the search, no search id could be reserved: {e}");
+            None
+        }
+    };
+
     let before = Instant::now();
     let trigrams: Vec<_> = TriGrams::new(cleanup_chars(query.chars())).collect();
     let trigrams = &trigrams[1..trigrams.len() - 1];
@@ -118,17 +442,42 @@ async fn search(
         }
     }
 
-    let title_bitmap = title_trigram_bitmaps.intersection();
-    let mut content_bitmap = content_trigram_bitmaps.intersection();
+    // The replaced documents stay in the postings until the indexer purges them
+    let deleted = database.deleted_docids(&rtxn).unwrap();
+    let title_bitmap = title_trigram_bitmaps.intersection() - &deleted;
+    let mut content_bitmap = content_trigram_bitmaps.intersection() - &deleted;
     content_bitmap -= &title_bitmap;
     let count = title_bitmap.union_len(&content_bitmap);
 
-    let mut results = Vec::new();
-    for (i, docid) in title_bitmap.into_iter().chain(content_bitmap).take(20).enumerate() {
+    let mut results: Vec<Result> = Vec::new();
+    let mut simhashes: Vec<Option<u64>> = Vec::new();
+    for docid in title_bitmap.into_iter().chain(content_bitmap).take(MAX_CANDIDATES) {
+        if results.len() == MAX_RESULTS {
+            break;
+        }
+
         if let Some(url) = database.docid_uri.get(&rtxn, &docid).unwrap() {
-            let title = url.to_string();
-            let link = generate_redirect_url(url, i, &query);
-            results.push(Result { link, title });
+            let metadata = database.docid_metadata.get(&rtxn, &docid).unwrap().unwrap_or_default();
+
+            // We only show the first document of a group of near-duplicates
+            if let Some(simhash) = metadata.simhash {
+                let representative = simhashes.iter().position(|h| {
+                    h.is_some_and(|h| hamming_distance(h, simhash) <= NEAR_DUPLICATE_DISTANCE)
+                });
+                if let Some(i) = representative {
+                    results[i].similar += 1;
+                    continue;
+                }
+            }
+
+            let title = match metadata.title {
+                Some(title) => title,
+                None => url.to_string(),
+            };
+            let link =
+                generate_redirect_url(&state.url_signer, docid, url, search_id, results.len());
+            results.push(Result { link, title, similar: 0 });
+            simhashes.push(metadata.simhash);
         }
     }
 
@@ -142,11 +491,53 @@ async fn search(
     .into_response()
 }
 
-async fn redirect(Query(params): Query<HashMap<String, String>>) -> Redirect {
-    match params.get("url") {
-        Some(url) => Redirect::temporary(url),
-        None => Redirect::temporary("/"),
+/// Only follows the links we signed or the URLs of the indexed
+/// documents, anything else leads to the home page.
+async fn redirect(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(address): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Query(params): Query<HashMap<String, String>>,
+) -> Redirect {
+    let database = &state.database;
+    let docid = params.get("docid").and_then(|docid| docid.parse().ok());
+    let search = params.get("search").and_then(|id| id.parse().ok());
+    let position = params.get("index").and_then(|index| index.parse().ok());
+    let click = search.zip(position);
+
+    let rtxn = database.read_txn().unwrap();
+    let target = match (docid, params.get("url"), params.get("sig")) {
+        (Some(docid), Some(url), Some(signature))
+            if state.url_signer.verify_link(docid, url, click, signature) =>
+        {
+            // The docid of the link may have been given to another document since
+            let docid = database.uri_docid(&rtxn, url).unwrap();
+            Some((url.clone(), docid, click))
+        }
+        // The clicks of the links that are not signed are not logged
+        _ => docid
+            .and_then(|docid| Some((database.docid_uri.get(&rtxn, &docid).unwrap()?, docid)))
+            .map(|(url, docid)| (url.to_owned(), Some(docid), None)),
+    };
+    drop(rtxn);
+
+    let Some((url, docid, click)) = target else { return Redirect::temporary("/") };
+
+    // Only the clicks on the results of a logged search are logged
+    if let Some((search, position)) = click {
+        let session = session_id(&state, address, &headers);
+        let timestamp = unix_timestamp();
+        let query = String::new();
+        state.log(LogEvent::Click(Click { search, query, position, docid, session, timestamp }));
     }
+
+    Redirect::temporary(&url)
+}
+
+/// The anonymous id of the session of a client, from its address and user agent.
+fn session_id(state: &AppState, address: SocketAddr, headers: &HeaderMap) -> String {
+    let user_agent = headers.get(header::USER_AGENT).and_then(|ua| ua.to_str().ok());
+    state.sessions.session_id(&format!("{} {}", address.ip(), user_agent.unwrap_or_default()))
 }
 
 async fn about() -> Redirect {
@@ -156,51 +547,374 @@ async fn about() -> Redirect {
 #[derive(Template)]
 #[template(path = "indexer.html")]
 struct IndexerTemplate {
-    tasks: Vec<Url>,
+    tasks: Vec<TaskEntry>,
+    /// Whether the indexer stopped claiming new tasks.
+    paused: bool,
+    /// The last processed tasks, the most recent first.
+    history: Vec<HistoryEntry>,
+    /// The token the forms must send back.
+    csrf: String,
+    /// Why the last submitted form was refused.
+    error: Option<String>,
+}
+
+struct TaskEntry {
+    key: TaskKey,
+    name: String,
+    link: Option<String>,
+    /// The priority of the task, shown when it is not the default one.
+    priority: Option<Priority>,
+    /// Whether the indexer is currently processing this task.
+    processing: bool,
+    /// What the indexer did so far on the processing task.
+    progress: Option<TaskProgress>,
+}
+
+struct HistoryEntry {
+    name: String,
+    link: Option<String>,
+    progress: TaskProgress,
+    /// The number of minutes since the task was done.
+    finished_minutes_ago: u64,
+    /// Why the task failed, none if it succeeded.
+    error: Option<String>,
 }
 
+/// The number of processed tasks shown on the indexer page.
+const HISTORY_SHOWN: usize = 50;
+
 async fn indexer(
-    // AuthBasic((id, password)): AuthBasic,
     State(state): State<Arc<AppState>>,
-) -> impl IntoResponse {
-    // if id == "admin" && password.map_or(false, |p| p == "53gb78855qdqsdlopnert") {
+    Extension(admin): Extension<Admin>,
+) -> IndexerTemplate {
+    indexer_page(&state, &admin, None)
+}
+
+fn indexer_page(state: &AppState, admin: &Admin, error: Option<String>) -> IndexerTemplate {
     let database = &state.database;
     let rtxn = database.read_txn().unwrap();
-    let tasks = database
-        .enqueued
-        .iter(&rtxn)
+    let processing = database.processing.iter(&rtxn).unwrap().map(|r| (r, true));
+    let enqueued = database.enqueued.iter(&rtxn).unwrap().map(|r| (r, false));
+    let tasks = processing
+        .chain(enqueued)
+        .flat_map(|(r, processing)| {
+            let (key, task) = r.ok()?;
+            let progress =
+                if processing { database.progress.get(&rtxn, &key).ok().flatten() } else { None };
+            Some(TaskEntry {
+                key,
+                name: task.to_string(),
+                link: task.kind.url().map(Url::to_string),
+                priority: Some(task.priority).filter(|p| *p != Priority::default()),
+                processing,
+                progress,
+            })
+        })
+        .collect();
+    let paused = database.is_paused(&rtxn).unwrap();
+    let now = unix_timestamp();
+    let history = database
+        .history
+        .rev_iter(&rtxn)
         .unwrap()
-        .flat_map(|r| r.ok().map(|(_, task)| task.url().clone()))
+        .take(HISTORY_SHOWN)
+        .flat_map(|r| {
+            r.ok().map(|(_, report)| HistoryEntry {
+                name: report.task.to_string(),
+                link: report.task.kind.url().map(Url::to_string),
+                progress: report.progress,
+                finished_minutes_ago: now.saturating_sub(report.finished_at) / 60,
+                error: report.error,
+            })
+        })
         .collect();
-    IndexerTemplate { tasks }.into_response()
-    // } else {
-    //     Redirect::temporary("/").into_response()
-    // }
+    let csrf = state.csrf_key.token(admin.name());
+    IndexerTemplate { tasks, paused, history, csrf, error }
 }
 
 #[derive(Deserialize)]
 struct WarcIdRegistering {
     #[serde(rename = "warcId")]
     warc_id: String,
+    /// Either `warc` or `wet`, the kind of files to index.
+    #[serde(default = "default_paths_format")]
+    format: String,
+    /// The priority of the crawl files in the queue.
+    #[serde(default)]
+    priority: Priority,
+    /// The indices of the first and last paths to index, all of them when empty.
+    #[serde(rename = "rangeStart", default)]
+    range_start: String,
+    #[serde(rename = "rangeEnd", default)]
+    range_end: String,
+    /// The segments to index separated by commas or spaces, all of them when empty.
+    #[serde(default)]
+    segments: String,
+    /// The number of randomly chosen paths to index, all of them when empty.
+    #[serde(rename = "sampleSize", default)]
+    sample_size: String,
+    /// The seed used to choose the paths, random when empty.
+    #[serde(rename = "sampleSeed", default)]
+    sample_seed: String,
+    #[serde(default)]
+    csrf: String,
+}
+
+impl WarcIdRegistering {
+    fn selection(&self) -> std::result::Result<PathsSelection, String> {
+        let start = parse_optional(&self.range_start).map_err(|e| format!("invalid start: {e}"))?;
+        let end = parse_optional(&self.range_end).map_err(|e| format!("invalid end: {e}"))?;
+        let range = match (start, end) {
+            (None, None) => None,
+            (Some(start), Some(end)) if start >= end => {
+                return Err(String::from("the first path must come before the last one"));
+            }
+            (start, end) => Some(start.unwrap_or(0)..end.unwrap_or(usize::MAX)),
+        };
+
+        let segments = self.segments.split([',', ' ']).filter(|s| !s.is_empty());
+        let segments = segments.map(str::to_owned).collect();
+
+        let size = parse_optional(&self.sample_size).map_err(|e| format!("invalid size: {e}"))?;
+        let seed = parse_optional(&self.sample_seed).map_err(|e| format!("invalid seed: {e}"))?;
+        let sample = size.map(|size| Sample { size, seed: seed.unwrap_or_else(rand::random) });
+
+        Ok(PathsSelection { range, segments, sample })
+    }
+}
+
+fn default_paths_format() -> String {
+    String::from("warc")
+}
+
+/// Parses a form field that can be left empty.
+fn parse_optional<T: FromStr>(value: &str) -> std::result::Result<Option<T>, T::Err> {
+    match value.trim() {
+        "" => Ok(None),
+        value => value.parse().map(Some),
+    }
 }
 
 async fn register_warc(
-    // AuthBasic((id, password)): AuthBasic,
     State(state): State<Arc<AppState>>,
-    Form(WarcIdRegistering { warc_id }): Form<WarcIdRegistering>,
-) -> Redirect {
-    eprintln!("Received {warc_id}");
-    // if id == "admin" && password.map_or(false, |p| p == "53gb78855qdqsdlopnert") {
+    Extension(admin): Extension<Admin>,
+    Form(registering): Form<WarcIdRegistering>,
+) -> Response {
+    let result = enqueue_crawl(&state, &admin, registering);
+    show_form_error(&state, &admin, result)
+}
+
+/// The errors are shown above the forms rather than on a blank page.
+fn show_form_error(state: &AppState, admin: &Admin, result: FormResponse) -> Response {
+    match result {
+        Ok(redirect) => redirect.into_response(),
+        Err((status, error)) => (status, indexer_page(state, admin, Some(error))).into_response(),
+    }
+}
+
+fn enqueue_crawl(state: &AppState, admin: &Admin, registering: WarcIdRegistering) -> FormResponse {
+    check_csrf(state, admin, &registering.csrf)?;
+    let selection = registering.selection().map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+    let WarcIdRegistering { warc_id, format, priority, .. } = registering;
+    let warc_id = warc_id.trim();
+    eprintln!("Received {warc_id:?} from {}", admin.name());
+    if !is_crawl_id(warc_id) {
+        let error = format!("{warc_id:?} is not a crawl id like CC-MAIN-2023-23");
+        return Err((StatusCode::BAD_REQUEST, error));
+    }
+    let wet = match format.as_str() {
+        "warc" => false,
+        "wet" => true,
+        _ => return Err((StatusCode::BAD_REQUEST, format!("unknown {format:?} files format"))),
+    };
+
+    let url = state.data_source.crawl_paths_url(warc_id, wet);
+    let url = url.map_err(|e| (StatusCode::BAD_REQUEST, format!("invalid crawl id: {e}")))?;
+    let paths = WarcPaths::new(url, selection);
+    let database = &state.database;
+    let mut wtxn = database.write_txn().unwrap();
+    let format = format.to_uppercase();
+    let files = if paths.selection.is_everything() {
+        format!("The {format} files of {warc_id}")
+    } else {
+        format!("The {format} files of {warc_id} ({})", paths.selection)
+    };
+    if database.is_crawl_in_queue(&wtxn, &paths).unwrap() {
+        return Err((StatusCode::CONFLICT, format!("{files} are already in the queue")));
+    }
+    let indexed_crawls = database.indexed_crawls(&wtxn).unwrap();
+    if paths.slice().is_some_and(|slice| indexed_crawls.contains(&slice)) {
+        return Err((StatusCode::CONFLICT, format!("{files} were already indexed")));
+    }
+
+    let task = Task::new(priority, TaskKind::WarcUrlPaths(paths));
+    database.enqueue_task(&mut wtxn, &task).unwrap();
+    wtxn.commit().unwrap();
+    wakeup::notify_indexer(&state.database_path);
+
+    Ok(Redirect::to("/indexer"))
+}
+
+#[derive(Deserialize)]
+struct DocumentsDeletion {
+    /// The docids or URLs of the documents to delete, one per line.
+    documents: String,
+    #[serde(default)]
+    csrf: String,
+}
+
+async fn delete_documents(
+    State(state): State<Arc<AppState>>,
+    Extension(admin): Extension<Admin>,
+    Form(DocumentsDeletion { documents, csrf }): Form<DocumentsDeletion>,
+) -> FormResponse {
+    check_csrf(&state, &admin, &csrf)?;
+    let database = &state.database;
+    let mut wtxn = database.write_txn().unwrap();
+    let mut docids = Vec::new();
+    for line in documents.lines().map(str::trim).filter(|l| !l.is_empty()) {
+        match line.parse() {
+            Ok(docid) => docids.push(docid),
+            Err(_) => match database.uri_docid(&wtxn, line).unwrap() {
+                Some(docid) => docids.push(docid),
+                None => eprintln!("Cannot delete {line:?}, it is not indexed"),
+            },
+        }
+    }
+
+    if !docids.is_empty() {
+        eprintln!("Received the deletion of {} documents", docids.len());
+        // Deleting documents is fast, it must not wait behind the crawls
+        let task = Task::new(Priority::High, TaskKind::DeleteDocuments(docids));
+        database.enqueue_task(&mut wtxn, &task).unwrap();
+        wtxn.commit().unwrap();
+        wakeup::notify_indexer(&state.database_path);
+    }
+
+    Ok(Redirect::to("/indexer"))
+}
+
+/// The enqueued task an action is about.
+#[derive(Deserialize)]
+struct TaskAction {
+    priority: Priority,
+    id: u64,
+    /// Whether the task was moved to the front of the queue.
+    #[serde(default)]
+    front: bool,
+    #[serde(default)]
+    csrf: String,
+}
+
+impl TaskAction {
+    fn key(&self) -> TaskKey {
+        TaskKey { priority: self.priority, id: self.id, front: self.front }
+    }
+}
+
+/// The error of an action on a task that is no longer enqueued.
+fn not_enqueued(key: TaskKey) -> (StatusCode, String) {
+    let error = format!("The task {} is no longer enqueued, it was processed or cancelled", key.id);
+    (StatusCode::CONFLICT, error)
+}
+
+async fn cancel_task(
+    State(state): State<Arc<AppState>>,
+    Extension(admin): Extension<Admin>,
+    Form(action): Form<TaskAction>,
+) -> Response {
+    let result = check_csrf(&state, &admin, &action.csrf).and_then(|()| {
+        let key = action.key();
+        let database = &state.database;
+        let mut wtxn = database.write_txn().unwrap();
+        if !database.cancel_task(&mut wtxn, key).unwrap() {
+            return Err(not_enqueued(key));
+        }
+        wtxn.commit().unwrap();
+        Ok(Redirect::to("/indexer"))
+    });
+    show_form_error(&state, &admin, result)
+}
+
+async fn move_task_to_front(
+    State(state): State<Arc<AppState>>,
+    Extension(admin): Extension<Admin>,
+    Form(action): Form<TaskAction>,
+) -> Response {
+    let result = check_csrf(&state, &admin, &action.csrf).and_then(|()| {
+        let key = action.key();
+        let database = &state.database;
+        let mut wtxn = database.write_txn().unwrap();
+        if database.move_task_to_front(&mut wtxn, key).unwrap().is_none() {
+            return Err(not_enqueued(key));
+        }
+        wtxn.commit().unwrap();
+        Ok(Redirect::to("/indexer"))
+    });
+    show_form_error(&state, &admin, result)
+}
+
+#[derive(Deserialize)]
+struct CrawlClearing {
+    #[serde(rename = "crawlId")]
+    crawl_id: String,
+    #[serde(default)]
+    csrf: String,
+}
+
+async fn clear_crawl(
+    State(state): State<Arc<AppState>>,
+    Extension(admin): Extension<Admin>,
+    Form(CrawlClearing { crawl_id, csrf }): Form<CrawlClearing>,
+) -> FormResponse {
+    check_csrf(&state, &admin, &csrf)?;
+    let crawl_id = crawl_id.trim();
+    if !is_crawl_id(crawl_id) {
+        let error = format!("{crawl_id:?} is not a crawl id like CC-MAIN-2023-23");
+        return Err((StatusCode::BAD_REQUEST, error));
+    }
+    let database = &state.database;
+    let mut wtxn = database.write_txn().unwrap();
+    let cleared = database.clear_crawl_tasks(&mut wtxn, crawl_id).unwrap();
+    wtxn.commit().unwrap();
+    eprintln!("Cleared {cleared} tasks of {crawl_id}");
+
+    Ok(Redirect::to("/indexer"))
+}
+
+#[derive(Deserialize)]
+struct IndexerControl {
+    #[serde(default)]
+    csrf: String,
+}
+
+async fn pause_indexer(
+    State(state): State<Arc<AppState>>,
+    Extension(admin): Extension<Admin>,
+    Form(IndexerControl { csrf }): Form<IndexerControl>,
+) -> FormResponse {
+    check_csrf(&state, &admin, &csrf)?;
+    set_indexer_paused(&state, true)
+}
+
+async fn resume_indexer(
+    State(state): State<Arc<AppState>>,
+    Extension(admin): Extension<Admin>,
+    Form(IndexerControl { csrf }): Form<IndexerControl>,
+) -> FormResponse {
+    check_csrf(&state, &admin, &csrf)?;
+    set_indexer_paused(&state, false)
+}
+
+fn set_indexer_paused(state: &AppState, paused: bool) -> FormResponse {
     let database = &state.database;
     let mut wtxn = database.write_txn().unwrap();
-    let task_id = database.available_reverse_enqueued_id(&wtxn).unwrap();
-    let url = format!("https://data.commoncrawl.org/crawl-data/{warc_id}/warc.paths.gz");
-    let task = Task::WarcUrlPaths(Url::parse(&url).unwrap());
-    database.enqueued.put(&mut wtxn, &task_id, &task).unwrap();
+    database.set_paused(&mut wtxn, paused).unwrap();
     wtxn.commit().unwrap();
-    // }
+    // A paused indexer waits for a wakeup to look at the flag again
+    wakeup::notify_indexer(&state.database_path);
 
-    Redirect::temporary("/indexer")
+    Ok(Redirect::to("/indexer"))
 }
 
 async fn assets_images_logo() -> impl IntoResponse {
@@ -209,12 +923,23 @@ async fn assets_images_logo() -> impl IntoResponse {
 }
 
 /// Generates a route that'll redirect to the link but we can have more info
-/// on the quality of the results for a given query.
-fn generate_redirect_url(url: &str, index: usize, query: &str) -> String {
-    format!(
-        "/redirect?url={}&index={}&query={}",
-        urlencoding::encode(url),
-        index,
-        urlencoding::encode(query)
-    )
+/// on the quality of the results for a given search. The link carries the URL, it
+/// keeps working when the docid is reused after a compaction as the docid of the URL
+/// is looked up again, and it is signed to prove it was generated by this server.
+fn generate_redirect_url(
+    signer: &UrlSigner,
+    docid: u64,
+    url: &str,
+    search: Option<u64>,
+    index: usize,
+) -> String {
+    // The clicks are only logged if the search was
+    let click = search.map(|search| (search, index as u32));
+    let signature = signer.sign_link(docid, url, click);
+    let mut link =
+        format!("/redirect?docid={docid}&url={}&sig={signature}", urlencoding::encode(url));
+    if let Some((search, index)) = click {
+        link.push_str(&format!("&search={search}&index={index}"));
+    }
+    link
 }
@@ -0,0 +1,159 @@
+//! Reading the CDX indexes of Common Crawl, they tell where the record
+//! of a URL is stored: the WARC file name, an offset and a length.
+
+use std::fmt;
+
+use serde::{Deserialize, Deserializer};
+use url::Url;
+
+/// A line of a CDX index, only the fields we use.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct CdxRecord {
+    pub url: String,
+    pub mime: Option<String>,
+    #[serde(default, deserialize_with = "status_from_string")]
+    pub status: Option<u16>,
+    /// The path of the WARC file, relative to the data source.
+    pub filename: String,
+    /// The position of the Gzipped record in the WARC file.
+    #[serde(deserialize_with = "u64_from_string")]
+    pub offset: u64,
+    #[serde(deserialize_with = "u64_from_string")]
+    pub length: u64,
+}
+
+impl CdxRecord {
+    /// Parses a line of a CDX file (`{surt} {timestamp} {json}`)
+    /// or of the JSON output of a CDX server (`{json}`).
+    pub fn from_line(line: &str) -> serde_json::Result<CdxRecord> {
+        let json = line.find('{').map_or(line, |i| &line[i..]);
+        serde_json::from_str(json)
+    }
+}
+
+/// The answer of a CDX server asked for the number of pages of
+/// the results with `showNumPages=true`, they are requested one by one.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct CdxPages {
+    pub pages: u64,
+}
+
+/// Common Crawl writes the numbers as strings.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum StringOrNumber {
+    String(String),
+    Number(u64),
+}
+
+fn u64_from_string<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u64, D::Error> {
+    match StringOrNumber::deserialize(deserializer)? {
+        StringOrNumber::String(s) => s.parse().map_err(serde::de::Error::custom),
+        StringOrNumber::Number(n) => Ok(n),
+    }
+}
+
+/// Some records, like revisits, have a `-` status.
+fn status_from_string<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<u16>, D::Error> {
+    match StringOrNumber::deserialize(deserializer)? {
+        StringOrNumber::String(s) => Ok(s.parse().ok()),
+        StringOrNumber::Number(n) => Ok(n.try_into().ok()),
+    }
+}
+
+/// The URL patterns understood by the Common Crawl index server:
+/// `*.gouv.fr` for a domain and its subdomains, `example.org/blog/*`
+/// for a prefix and anything else for a single URL.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UrlPattern {
+    Domain(String),
+    Prefix(String),
+    Exact(String),
+}
+
+impl UrlPattern {
+    pub fn parse(pattern: &str) -> UrlPattern {
+        let pattern = without_scheme(pattern.trim());
+        if let Some(domain) = pattern.strip_prefix("*.") {
+            UrlPattern::Domain(domain.trim_end_matches('/').to_lowercase())
+        } else if let Some(prefix) = pattern.strip_suffix('*') {
+            UrlPattern::Prefix(prefix.to_owned())
+        } else {
+            UrlPattern::Exact(pattern.to_owned())
+        }
+    }
+
+    pub fn matches(&self, url: &Url) -> bool {
+        match self {
+            UrlPattern::Domain(domain) => url.host_str().is_some_and(|host| {
+                host == domain
+                    || host.strip_suffix(domain.as_str()).is_some_and(|sub| sub.ends_with('.'))
+            }),
+            UrlPattern::Prefix(prefix) => without_scheme(url.as_str()).starts_with(prefix),
+            UrlPattern::Exact(exact) => {
+                without_scheme(url.as_str()).trim_end_matches('/') == exact.trim_end_matches('/')
+            }
+        }
+    }
+}
+
+impl fmt::Display for UrlPattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UrlPattern::Domain(domain) => write!(f, "*.{domain}"),
+            UrlPattern::Prefix(prefix) => write!(f, "{prefix}*"),
+            UrlPattern::Exact(exact) => f.write_str(exact),
+        }
+    }
+}
+
+fn without_scheme(url: &str) -> &str {
+    url.split_once("://").map_or(url, |(_, rest)| rest)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_cdx_lines() {
+        let line = r#"fr,gouv,service-public)/ 20230601123456 {"url": "https://www.service-public.fr/", "mime": "text/html", "status": "200", "digest": "ABC", "length": "1234", "offset": "5678", "filename": "crawl-data/CC-MAIN-2023-23/segments/1/warc/a.warc.gz"}"#;
+        let record = CdxRecord::from_line(line).unwrap();
+        assert_eq!(record.url, "https://www.service-public.fr/");
+        assert_eq!(record.status, Some(200));
+        assert_eq!((record.offset, record.length), (5678, 1234));
+
+        let line = r#"{"url": "https://a.gouv.fr/", "status": "-", "length": 10, "offset": 0, "filename": "a.warc.gz"}"#;
+        let record = CdxRecord::from_line(line).unwrap();
+        assert_eq!(record.status, None);
+        assert_eq!(record.mime, None);
+        assert_eq!((record.offset, record.length), (0, 10));
+
+        assert!(CdxRecord::from_line("fr,gouv)/ 2023").is_err());
+
+        let pages: CdxPages =
+            serde_json::from_str(r#"{"pages": 3, "pageSize": 5, "blocks": 12}"#).unwrap();
+        assert_eq!(pages, CdxPages { pages: 3 });
+    }
+
+    #[test]
+    fn url_patterns() {
+        let url = |s| Url::parse(s).unwrap();
+
+        let domain = UrlPattern::parse("*.gouv.fr");
+        assert_eq!(domain, UrlPattern::Domain(String::from("gouv.fr")));
+        assert!(domain.matches(&url("https://gouv.fr/")));
+        assert!(domain.matches(&url("https://www.service-public.gouv.fr/a")));
+        assert!(!domain.matches(&url("https://notgouv.fr/")));
+
+        let prefix = UrlPattern::parse("https://example.org/blog/*");
+        assert_eq!(prefix, UrlPattern::Prefix(String::from("example.org/blog/")));
+        assert!(prefix.matches(&url("http://example.org/blog/post")));
+        assert!(!prefix.matches(&url("http://example.org/about")));
+
+        let exact = UrlPattern::parse("example.org/about");
+        assert!(exact.matches(&url("https://example.org/about/")));
+        assert!(!exact.matches(&url("https://example.org/about/team")));
+        assert_eq!(exact.to_string(), "example.org/about");
+    }
+}
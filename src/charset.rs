@@ -0,0 +1,138 @@
+//! Detection of the charset of HTML pages and transcoding to UTF-8.
+//!
+//! The charset is found by looking, in order, at the byte order mark, the `charset`
+//! parameter of the `Content-Type` header, the `<meta charset>` tags and then by
+//! sniffing the bytes of the page.
+
+use std::borrow::Cow;
+
+use chardetng::EncodingDetector;
+use encoding_rs::{Encoding, UTF_16BE, UTF_16LE, UTF_8};
+use url::Url;
+
+/// The number of bytes in which we look for a `<meta charset>` tag,
+/// the same limit as the prescan of the HTML specification.
+const META_PRESCAN_LENGTH: usize = 1024;
+
+/// Decodes an HTML body into UTF-8, the URL is used to help the sniffing.
+pub fn decode_html<'a>(body: &'a [u8], content_type: Option<&str>, url: &Url) -> Cow<'a, str> {
+    let encoding = detect_charset(body, content_type, url);
+    let (text, _encoding, _had_errors) = encoding.decode(body);
+    text
+}
+
+/// Returns the charset of an HTML body.
+pub fn detect_charset(body: &[u8], content_type: Option<&str>, url: &Url) -> &'static Encoding {
+    if let Some((encoding, _)) = Encoding::for_bom(body) {
+        return encoding;
+    }
+
+    if let Some(encoding) = content_type.and_then(header_charset) {
+        return encoding;
+    }
+
+    if let Some(encoding) = meta_charset(body) {
+        // A page that declares itself as UTF-16 in ASCII is not UTF-16
+        return if encoding == UTF_16LE || encoding == UTF_16BE { UTF_8 } else { encoding };
+    }
+
+    let mut detector = EncodingDetector::new();
+    detector.feed(body, true);
+    let tld = url.domain().and_then(|d| d.rsplit('.').next()).map(str::as_bytes);
+    detector.guess(tld, true)
+}
+
+/// Returns the encoding of the `charset` parameter of a `Content-Type` header value.
+fn header_charset(content_type: &str) -> Option<&'static Encoding> {
+    content_type.split(';').skip(1).find_map(|param| {
+        let (name, value) = param.split_once('=')?;
+        if name.trim().eq_ignore_ascii_case("charset") {
+            Encoding::for_label(value.trim().trim_matches(|c| c == '"' || c == '\'').as_bytes())
+        } else {
+            None
+        }
+    })
+}
+
+/// Returns the encoding declared by the first `<meta>` tag with a charset,
+/// either `<meta charset="...">` or `<meta http-equiv="Content-Type" content="...; charset=...">`.
+fn meta_charset(body: &[u8]) -> Option<&'static Encoding> {
+    let head = &body[..body.len().min(META_PRESCAN_LENGTH)];
+    let head = head.to_ascii_lowercase();
+    let mut offset = 0;
+    while let Some(start) = find(&head[offset..], b"<meta") {
+        let tag_start = offset + start;
+        let tag_end = find(&head[tag_start..], b">").map_or(head.len(), |end| tag_start + end);
+        let tag = &head[tag_start..tag_end];
+        if let Some(position) = find(tag, b"charset=") {
+            let value = &tag[position + b"charset=".len()..];
+            let value =
+                value.strip_prefix(b"\"").or_else(|| value.strip_prefix(b"'")).unwrap_or(value);
+            let end = value
+                .iter()
+                .position(|b| {
+                    matches!(b, b'"' | b'\'' | b';' | b'/' | b'>') || b.is_ascii_whitespace()
+                })
+                .unwrap_or(value.len());
+            if let Some(encoding) = Encoding::for_label(&value[..end]) {
+                return Some(encoding);
+            }
+        }
+        offset = tag_end;
+    }
+    None
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|window| window == needle)
+}
+
+#[cfg(test)]
+mod tests {
+    use encoding_rs::{ISO_8859_2, WINDOWS_1252};
+
+    use super::*;
+
+    fn url() -> Url {
+        Url::parse("https://boulangerie.fr/").unwrap()
+    }
+
+    #[test]
+    fn content_type_header() {
+        let body = b"<html><body>Caf\xe9 cr\xe8me</body></html>";
+        let content_type = Some("text/html; charset=ISO-8859-1");
+        assert_eq!(detect_charset(body, content_type, &url()), WINDOWS_1252);
+        assert_eq!(decode_html(body, content_type, &url()), "<html><body>Café crème</body></html>");
+    }
+
+    #[test]
+    fn meta_tags() {
+        let body = b"<html><head><META charset=\"iso-8859-2\"></head></html>";
+        assert_eq!(detect_charset(body, Some("text/html"), &url()), ISO_8859_2);
+
+        let body =
+            b"<meta http-equiv=\"Content-Type\" content=\"text/html; charset=windows-1252\">";
+        assert_eq!(detect_charset(body, None, &url()), WINDOWS_1252);
+
+        let body = b"<meta name=\"viewport\"><meta charset=utf-16>";
+        assert_eq!(detect_charset(body, None, &url()), UTF_8);
+    }
+
+    #[test]
+    fn byte_order_mark() {
+        let body = b"\xef\xbb\xbfCaf\xc3\xa9";
+        assert_eq!(detect_charset(body, Some("text/html; charset=windows-1252"), &url()), UTF_8);
+        assert_eq!(decode_html(body, None, &url()), "Café");
+    }
+
+    #[test]
+    fn sniffing() {
+        let body = "<html><body><p>Les pâtisseries françaises sont réputées à travers le \
+                    monde entier, le croissant et le pain au chocolat sont élaborés avec \
+                    une pâte feuilletée levée très légère.</p></body></html>";
+        let (latin1, _, _) = WINDOWS_1252.encode(body);
+        assert_eq!(detect_charset(&latin1, None, &url()), WINDOWS_1252);
+        assert_eq!(decode_html(&latin1, None, &url()), body);
+        assert_eq!(detect_charset(body.as_bytes(), None, &url()), UTF_8);
+    }
+}
@@ -0,0 +1,174 @@
+//! The searches made on the search server and the results clicked, used to measure
+//! the quality of the results with the click-through rate and the mean reciprocal rank.
+
+use std::collections::{BTreeMap, HashMap};
+
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use ring::hmac;
+use serde::{Deserialize, Serialize};
+
+use crate::progress::unix_timestamp;
+
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+/// A search made by a user.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct SearchLog {
+    pub query: String,
+    pub session: String,
+    /// When the search was made, in seconds since the Unix epoch.
+    pub timestamp: u64,
+}
+
+/// A result of a search clicked by a user.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Click {
+    /// The id of the search the result was part of.
+    pub search: u64,
+    /// The query of the search, copied from the logged search.
+    pub query: String,
+    /// The position of the result in the page, starting at zero.
+    pub position: u32,
+    /// The clicked document, none once it is deleted and the docids are compacted.
+    pub docid: Option<u64>,
+    pub session: String,
+    /// When the result was clicked, in seconds since the Unix epoch.
+    pub timestamp: u64,
+}
+
+/// Derives anonymous session ids from what identifies a client, the ids change every
+/// day and cannot be reversed without the key so that the users cannot be tracked.
+pub struct Sessions {
+    key: hmac::Key,
+}
+
+impl Sessions {
+    pub fn new(secret: &[u8]) -> Sessions {
+        Sessions { key: hmac::Key::new(hmac::HMAC_SHA256, secret) }
+    }
+
+    /// Returns the session id of a client, e.g. its address and user agent.
+    pub fn session_id(&self, client: &str) -> String {
+        let day = unix_timestamp() / SECONDS_PER_DAY;
+        let signature = hmac::sign(&self.key, format!("{day}:{client}").as_bytes());
+        URL_SAFE_NO_PAD.encode(&signature.as_ref()[..12])
+    }
+}
+
+/// How the users reacted to the results of a query.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct QueryStats {
+    pub searches: u64,
+    /// The number of searches with at least one clicked result.
+    pub clicked_searches: u64,
+    pub clicks: u64,
+    /// The sum of the reciprocal ranks of the first clicked result of the searches.
+    reciprocal_ranks: f64,
+}
+
+impl QueryStats {
+    /// The fraction of the searches with at least one clicked result.
+    pub fn click_through_rate(&self) -> f64 {
+        if self.searches == 0 {
+            0.0
+        } else {
+            self.clicked_searches as f64 / self.searches as f64
+        }
+    }
+
+    /// The mean of the reciprocal ranks of the first clicked result,
+    /// the searches without any click count as zero.
+    pub fn mean_reciprocal_rank(&self) -> f64 {
+        if self.searches == 0 {
+            0.0
+        } else {
+            self.reciprocal_ranks / self.searches as f64
+        }
+    }
+}
+
+/// Computes the stats of the queries, grouped case-insensitively,
+/// the clicks of unknown searches are ignored.
+pub fn query_stats(
+    searches: impl IntoIterator<Item = (u64, SearchLog)>,
+    clicks: impl IntoIterator<Item = Click>,
+) -> BTreeMap<String, QueryStats> {
+    let mut stats = BTreeMap::<String, QueryStats>::new();
+    let mut queries = HashMap::new();
+    for (id, search) in searches {
+        let query = search.query.trim().to_lowercase();
+        stats.entry(query.clone()).or_default().searches += 1;
+        queries.insert(id, query);
+    }
+
+    // The best position clicked in every search
+    let mut first_clicks = HashMap::<u64, u32>::new();
+    for click in clicks {
+        let Some(query) = queries.get(&click.search) else { continue };
+        stats.get_mut(query).unwrap().clicks += 1;
+        let position = first_clicks.entry(click.search).or_insert(click.position);
+        *position = click.position.min(*position);
+    }
+
+    for (search, position) in first_clicks {
+        let query_stats = stats.get_mut(&queries[&search]).unwrap();
+        query_stats.clicked_searches += 1;
+        query_stats.reciprocal_ranks += 1.0 / (position as f64 + 1.0);
+    }
+
+    stats
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sessions_are_anonymous() {
+        let sessions = Sessions::new(b"secret");
+        let id = sessions.session_id("127.0.0.1 curl/8.0");
+        assert_eq!(id, sessions.session_id("127.0.0.1 curl/8.0"));
+        assert_ne!(id, sessions.session_id("127.0.0.2 curl/8.0"));
+        assert_ne!(id, Sessions::new(b"other").session_id("127.0.0.1 curl/8.0"));
+        assert!(!id.contains("127.0.0.1"));
+    }
+
+    #[test]
+    fn click_through_rates_and_reciprocal_ranks() {
+        let search = |query: &str| SearchLog {
+            query: query.to_owned(),
+            session: "session".to_owned(),
+            timestamp: 0,
+        };
+        let click = |search, position| Click {
+            search,
+            query: String::new(),
+            position,
+            docid: None,
+            session: "session".to_owned(),
+            timestamp: 0,
+        };
+
+        let searches = [
+            (0, search("croissant")),
+            (1, search("Croissant ")),
+            (2, search("croissant")),
+            (3, search("croissant")),
+            (4, search("baguette")),
+        ];
+        // The second click of the first search doesn't change its rank
+        let clicks = [click(0, 0), click(0, 3), click(1, 1), click(2, 3), click(9, 0)];
+        let stats = query_stats(searches, clicks);
+
+        assert_eq!(stats.len(), 2);
+        let croissant = &stats["croissant"];
+        assert_eq!((croissant.searches, croissant.clicked_searches, croissant.clicks), (4, 3, 4));
+        assert_eq!(croissant.click_through_rate(), 0.75);
+        assert_eq!(croissant.mean_reciprocal_rank(), (1.0 + 0.5 + 0.25) / 4.0);
+        let baguette = &stats["baguette"];
+        assert_eq!((baguette.searches, baguette.clicks), (1, 0));
+        assert_eq!(baguette.click_through_rate(), 0.0);
+        assert_eq!(baguette.mean_reciprocal_rank(), 0.0);
+    }
+}
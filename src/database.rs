@@ -1,11 +1,21 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::ops::Range;
 use std::path::Path;
 
 use heed::byteorder::BE;
-use heed::types::{DecodeIgnore, SerdeJson, Str, U32, U64};
+use heed::types::{ByteSlice, SerdeJson, Str, Unit, U32, U64};
 use heed::{Env, EnvOpenOptions, RoTxn, RwTxn, Unspecified};
+use ring::digest;
 use roaring::RoaringTreemap;
+use serde::{Deserialize, Serialize};
+use url::Url;
 
-use crate::task::Task;
+use crate::auth::{api_token_digest, generate_api_token, generate_secret, ApiToken};
+use crate::clicks::{query_stats, Click, QueryStats, SearchLog};
+use crate::document::DocumentMetadata;
+use crate::progress::{unix_timestamp, TaskProgress, TaskReport};
+use crate::task::{Priority, Task, TaskKey, TaskKind, WarcPaths};
+use crate::task_key_codec::TaskKeyCodec;
 use crate::treemap_codec::RoaringTreemapCodec;
 
 pub struct Database {
@@ -14,25 +24,87 @@ pub struct Database {
     pub title_ngrams_docids: heed::Database<Str, RoaringTreemapCodec>,
     pub content_ngrams_docids: heed::Database<Str, RoaringTreemapCodec>,
     pub docid_uri: heed::Database<U64<BE>, Str>,
-    pub enqueued: heed::Database<U32<BE>, SerdeJson<Task>>,
+    /// The docids by the digest of their URL, LMDB keys can't be longer than 511 bytes
+    /// and URLs can, use [`Database::uri_docid`] to find the docid of a URL.
+    pub uri_digest_docid: heed::Database<ByteSlice, U64<BE>>,
+    pub docid_metadata: heed::Database<U64<BE>, SerdeJson<DocumentMetadata>>,
+    pub docid_outlinks: heed::Database<U64<BE>, SerdeJson<Vec<Url>>>,
+    /// The tasks to process, the ones moved to the front first, then by priority and id.
+    pub enqueued: heed::Database<TaskKeyCodec, SerdeJson<Task>>,
+    /// The tasks claimed by the indexer and not yet committed.
+    pub processing: heed::Database<TaskKeyCodec, SerdeJson<Task>>,
+    /// The progress of the processing tasks.
+    pub progress: heed::Database<TaskKeyCodec, SerdeJson<TaskProgress>>,
+    /// The last processed tasks, by the order in which they were done.
+    pub history: heed::Database<U64<BE>, SerdeJson<TaskReport>>,
+    /// The API tokens of the admins by their digest, the tokens themselves are not stored.
+    api_tokens: heed::Database<Str, SerdeJson<ApiToken>>,
+    /// The searches made on the search server, by the order in which they were made.
+    pub searches: heed::Database<U64<BE>, SerdeJson<SearchLog>>,
+    /// The results clicked by the users, by the order in which they were clicked.
+    pub clicks: heed::Database<U64<BE>, SerdeJson<Click>>,
+    /// The queue before the tasks had a priority, only read to migrate it.
+    legacy_enqueued: heed::Database<U32<BE>, SerdeJson<TaskKind>>,
+}
+
+/// The files of a crawl slice that are enqueued or being processed.
+#[derive(Deserialize, Serialize)]
+struct PendingCrawlSlice {
+    remaining: u64,
+    /// Whether one of the files failed or was cancelled.
+    failed: bool,
 }
 
 impl Database {
+    /// The number of processed tasks kept in the history.
+    pub const HISTORY_SIZE: u64 = 1000;
+
+    /// The share of the documents that can stay in the postings once deleted,
+    /// purging them scans and rewrites all the postings.
+    pub const MAX_DELETED_SHARE: f64 = 0.1;
+
     pub fn open_or_create(
         mut options: EnvOpenOptions,
         path: impl AsRef<Path>,
     ) -> heed::Result<Database> {
-        let env = options.max_dbs(10).open(path)?;
+        let env = options.max_dbs(16).open(path)?;
         let mut wtxn = env.write_txn()?;
         let main = env.create_database(&mut wtxn, None)?;
         let title_ngrams_docids = env.create_database(&mut wtxn, Some("title-ngrams-docids"))?;
         let content_ngrams_docids =
             env.create_database(&mut wtxn, Some("content-ngrams-docids"))?;
         let docid_uri = env.create_database(&mut wtxn, Some("docid-uri"))?;
-        let enqueued = env.create_database(&mut wtxn, Some("enqueued"))?;
+        let uri_digest_docid = env.create_database(&mut wtxn, Some("uri-digest-docid"))?;
+        let docid_metadata = env.create_database(&mut wtxn, Some("docid-metadata"))?;
+        let docid_outlinks = env.create_database(&mut wtxn, Some("docid-outlinks"))?;
+        let enqueued = env.create_database(&mut wtxn, Some("enqueued-tasks"))?;
+        let processing = env.create_database(&mut wtxn, Some("processing-tasks"))?;
+        let progress = env.create_database(&mut wtxn, Some("tasks-progress"))?;
+        let history = env.create_database(&mut wtxn, Some("tasks-history"))?;
+        let api_tokens = env.create_database(&mut wtxn, Some("api-tokens"))?;
+        let searches = env.create_database(&mut wtxn, Some("searches-log"))?;
+        let clicks = env.create_database(&mut wtxn, Some("clicks-log"))?;
+        let legacy_enqueued = env.create_database(&mut wtxn, Some("enqueued"))?;
         wtxn.commit()?;
 
-        Ok(Database { env, main, title_ngrams_docids, content_ngrams_docids, docid_uri, enqueued })
+        Ok(Database {
+            env,
+            main,
+            title_ngrams_docids,
+            content_ngrams_docids,
+            docid_uri,
+            uri_digest_docid,
+            docid_metadata,
+            docid_outlinks,
+            enqueued,
+            processing,
+            progress,
+            history,
+            api_tokens,
+            searches,
+            clicks,
+            legacy_enqueued,
+        })
     }
 
     pub fn read_txn(&self) -> heed::Result<RoTxn> {
@@ -54,14 +126,1018 @@ impl Database {
         self.main.remap_types::<Str, RoaringTreemapCodec>().put(wtxn, "all-docids", bitmap)
     }
 
-    pub fn available_reverse_enqueued_id(&self, rtxn: &RoTxn) -> heed::Result<u32> {
-        let iter = self.enqueued.rev_iter(rtxn)?.remap_data_type::<DecodeIgnore>();
-        for (result, expected) in iter.zip((0..=u32::MAX).rev()) {
-            let (task_id, _) = result?;
-            if task_id != expected {
-                return Ok(expected);
+    /// The documents that were deleted but are still part of the postings,
+    /// they must be ignored by the searches until they are purged from them.
+    pub fn deleted_docids(&self, rtxn: &RoTxn) -> heed::Result<RoaringTreemap> {
+        self.main
+            .remap_types::<Str, RoaringTreemapCodec>()
+            .get(rtxn, "deleted-docids")
+            .map(Option::unwrap_or_default)
+    }
+
+    fn put_deleted_docids(&self, wtxn: &mut RwTxn, bitmap: &RoaringTreemap) -> heed::Result<()> {
+        self.main.remap_types::<Str, RoaringTreemapCodec>().put(wtxn, "deleted-docids", bitmap)
+    }
+
+    /// Removes the documents from the URLs, the metadata and the list of all the
+    /// documents. The postings are not scanned, the docids are marked as deleted
+    /// until enough of them are purged at once, see [`Database::purge_deleted_docids`].
+    pub fn delete_documents(&self, wtxn: &mut RwTxn, docids: &RoaringTreemap) -> heed::Result<()> {
+        if docids.is_empty() {
+            return Ok(());
+        }
+
+        for docid in docids {
+            if let Some(uri) = self.docid_uri.get(wtxn, &docid)? {
+                let uri = uri.to_owned();
+                self.delete_uri_docid(wtxn, &uri, docid)?;
+            }
+            self.docid_uri.delete(wtxn, &docid)?;
+            self.docid_metadata.delete(wtxn, &docid)?;
+            self.docid_outlinks.delete(wtxn, &docid)?;
+        }
+
+        // Only the documents that were indexed can be part of the postings
+        let mut all_docids = self.all_docids(wtxn)?;
+        let indexed = docids & &all_docids;
+        if indexed.is_empty() {
+            return Ok(());
+        }
+
+        let deleted = self.deleted_docids(wtxn)? | &indexed;
+        self.put_deleted_docids(wtxn, &deleted)?;
+        all_docids -= indexed;
+        self.put_all_docids(wtxn, &all_docids)
+    }
+
+    /// Whether the deleted documents still in the postings are numerous enough to be
+    /// worth purging, see [`Database::MAX_DELETED_SHARE`].
+    pub fn should_purge_deleted_docids(&self, rtxn: &RoTxn) -> heed::Result<bool> {
+        let deleted = self.deleted_docids(rtxn)?.len();
+        let total = self.all_docids(rtxn)?.len() + deleted;
+        Ok(deleted != 0 && deleted as f64 >= total as f64 * Self::MAX_DELETED_SHARE)
+    }
+
+    /// Removes the deleted documents from the postings, their docids can then be
+    /// given to new documents. Returns the number of purged documents.
+    pub fn purge_deleted_docids(&self, wtxn: &mut RwTxn) -> heed::Result<u64> {
+        let deleted = self.deleted_docids(wtxn)?;
+        if deleted.is_empty() {
+            return Ok(0);
+        }
+
+        for postings in [self.title_ngrams_docids, self.content_ngrams_docids] {
+            let mut iter = postings.iter_mut(wtxn)?;
+            while let Some(result) = iter.next() {
+                let (ngram, mut bitmap) = result?;
+                if bitmap.is_disjoint(&deleted) {
+                    continue;
+                }
+                bitmap -= &deleted;
+                let ngram = ngram.to_owned();
+                // safety: we don't keep any reference to the database content
+                unsafe {
+                    if bitmap.is_empty() {
+                        iter.del_current()?;
+                    } else {
+                        iter.put_current(&ngram, &bitmap)?;
+                    }
+                }
+            }
+        }
+        self.put_deleted_docids(wtxn, &RoaringTreemap::new())?;
+
+        Ok(deleted.len())
+    }
+
+    /// Renumbers the documents so that the docids go from zero to the number
+    /// of documents without any hole, returns the number of renumbered documents.
+    ///
+    /// Returns `None` without renumbering anything while the indexer processes
+    /// a deletion, it holds the docids to delete and we can't change them.
+    pub fn compact_docids(&self, wtxn: &mut RwTxn) -> heed::Result<Option<u64>> {
+        for result in self.processing.iter(wtxn)? {
+            let (_, task) = result?;
+            if matches!(task.kind, TaskKind::DeleteDocuments(_)) {
+                return Ok(None);
+            }
+        }
+
+        let all_docids = self.all_docids(wtxn)?;
+        self.delete_unknown_docids(wtxn, &all_docids)?;
+
+        // The rank is the number of docids lower than or equal to the given one
+        let new_docid = |docid: u64| all_docids.rank(docid) - 1;
+
+        // The new docid of a document is always lower than or equal to the old one
+        // and was either free or moved away before, we can move them in place.
+        let mut renumbered = 0;
+        for (new, old) in (0..).zip(all_docids.iter()) {
+            if new == old {
+                continue;
+            }
+
+            if let Some(uri) = self.docid_uri.get(wtxn, &old)? {
+                let uri = uri.to_owned();
+                self.docid_uri.delete(wtxn, &old)?;
+                self.docid_uri.put(wtxn, &new, &uri)?;
+                self.put_uri_docid(wtxn, &uri, new)?;
+            }
+            if let Some(metadata) = self.docid_metadata.get(wtxn, &old)? {
+                self.docid_metadata.delete(wtxn, &old)?;
+                self.docid_metadata.put(wtxn, &new, &metadata)?;
+            }
+            if let Some(outlinks) = self.docid_outlinks.get(wtxn, &old)? {
+                self.docid_outlinks.delete(wtxn, &old)?;
+                self.docid_outlinks.put(wtxn, &new, &outlinks)?;
+            }
+            renumbered += 1;
+        }
+
+        // Only the indexed documents are renumbered, the deleted ones and the unknown
+        // docids left by a crashed write or an older version are removed from the postings
+        for postings in [self.title_ngrams_docids, self.content_ngrams_docids] {
+            let mut iter = postings.iter_mut(wtxn)?;
+            while let Some(result) = iter.next() {
+                let (ngram, mut bitmap) = result?;
+                let len = bitmap.len();
+                bitmap &= &all_docids;
+                if renumbered == 0 && bitmap.len() == len {
+                    continue;
+                }
+                let bitmap =
+                    RoaringTreemap::from_sorted_iter(bitmap.iter().map(new_docid)).unwrap();
+                let ngram = ngram.to_owned();
+                // safety: we don't keep any reference to the database content
+                unsafe {
+                    if bitmap.is_empty() {
+                        iter.del_current()?;
+                    } else {
+                        iter.put_current(&ngram, &bitmap)?;
+                    }
+                }
+            }
+        }
+        self.put_deleted_docids(wtxn, &RoaringTreemap::new())?;
+
+        self.remap_clicked_docids(wtxn, |docid| {
+            all_docids.contains(docid).then(|| new_docid(docid))
+        })?;
+
+        // The enqueued deletions must target the new docids, the
+        // documents that no longer exist don't need to be deleted.
+        let mut iter = self.enqueued.iter_mut(wtxn)?;
+        while let Some(result) = iter.next() {
+            let (key, mut task) = result?;
+            if let TaskKind::DeleteDocuments(docids) = &task.kind {
+                let docids = docids
+                    .iter()
+                    .filter(|docid| all_docids.contains(**docid))
+                    .map(|docid| new_docid(*docid))
+                    .collect();
+                task.kind = TaskKind::DeleteDocuments(docids);
+                // safety: we don't keep any reference to the database content
+                unsafe { iter.put_current(&key, &task)? };
+            }
+        }
+        drop(iter);
+
+        let compacted: RoaringTreemap = (0..all_docids.len()).collect();
+        self.put_all_docids(wtxn, &compacted)?;
+
+        Ok(Some(renumbered))
+    }
+
+    /// Removes the URLs, metadata and outlinks of the docids that are not indexed,
+    /// e.g. left by a crashed write, the renumbered documents would collide with them.
+    fn delete_unknown_docids(
+        &self,
+        wtxn: &mut RwTxn,
+        all_docids: &RoaringTreemap,
+    ) -> heed::Result<()> {
+        let docid_uri = self.docid_uri.remap_data_type::<ByteSlice>();
+        let docid_metadata = self.docid_metadata.remap_data_type::<ByteSlice>();
+        let docid_outlinks = self.docid_outlinks.remap_data_type::<ByteSlice>();
+        for database in [docid_uri, docid_metadata, docid_outlinks] {
+            let mut iter = database.iter_mut(wtxn)?;
+            while let Some(result) = iter.next() {
+                let (docid, _) = result?;
+                if !all_docids.contains(docid) {
+                    // safety: we don't keep any reference to the database content
+                    unsafe { iter.del_current()? };
+                }
+            }
+        }
+
+        let mut iter = self.uri_digest_docid.iter_mut(wtxn)?;
+        while let Some(result) = iter.next() {
+            let (_, docid) = result?;
+            if !all_docids.contains(docid) {
+                // safety: we don't keep any reference to the database content
+                unsafe { iter.del_current()? };
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Changes the docids of the clicked documents, the clicks themselves are kept.
+    fn remap_clicked_docids(
+        &self,
+        wtxn: &mut RwTxn,
+        remap: impl Fn(u64) -> Option<u64>,
+    ) -> heed::Result<()> {
+        let mut iter = self.clicks.iter_mut(wtxn)?;
+        while let Some(result) = iter.next() {
+            let (id, mut click) = result?;
+            let Some(docid) = click.docid else { continue };
+            click.docid = remap(docid);
+            if click.docid != Some(docid) {
+                // safety: we don't keep any reference to the database content
+                unsafe { iter.put_current(&id, &click)? };
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the docid of the document with this URL, if it is indexed.
+    pub fn uri_docid(&self, rtxn: &RoTxn, uri: &str) -> heed::Result<Option<u64>> {
+        let Some(docid) = self.uri_digest_docid.get(rtxn, &uri_digest(uri))? else {
+            return Ok(None);
+        };
+        // Only the URL itself proves that the digest wasn't the one of another URL
+        Ok(self.docid_uri.get(rtxn, &docid)?.filter(|stored| *stored == uri).map(|_| docid))
+    }
+
+    pub fn put_uri_docid(&self, wtxn: &mut RwTxn, uri: &str, docid: u64) -> heed::Result<()> {
+        self.uri_digest_docid.put(wtxn, &uri_digest(uri), &docid)
+    }
+
+    /// Forgets that this URL leads to this document, the URL
+    /// may now be associated to a newer version of the document.
+    fn delete_uri_docid(&self, wtxn: &mut RwTxn, uri: &str, docid: u64) -> heed::Result<()> {
+        let digest = uri_digest(uri);
+        if self.uri_digest_docid.get(wtxn, &digest)? == Some(docid) {
+            self.uri_digest_docid.delete(wtxn, &digest)?;
+        }
+        Ok(())
+    }
+
+    /// Fills the URL to docid reverse mapping from the docid to URL one,
+    /// useful for databases created before the reverse mapping existed.
+    pub fn rebuild_uri_docid(&self, wtxn: &mut RwTxn) -> heed::Result<()> {
+        self.uri_digest_docid.clear(wtxn)?;
+        let mut entries = Vec::new();
+        for result in self.docid_uri.iter(wtxn)? {
+            let (docid, uri) = result?;
+            entries.push((docid, uri.to_owned()));
+        }
+        for (docid, uri) in entries {
+            self.put_uri_docid(wtxn, &uri, docid)?;
+        }
+        Ok(())
+    }
+
+    /// Returns a new task id, the ids are never reused.
+    fn next_task_id(&self, wtxn: &mut RwTxn) -> heed::Result<u64> {
+        let main = self.main.remap_types::<Str, U64<BE>>();
+        let id = main.get(wtxn, "next-task-id")?.unwrap_or_default();
+        main.put(wtxn, "next-task-id", &(id + 1))?;
+        Ok(id)
+    }
+
+    /// Adds a task at the end of the tasks of the same priority.
+    pub fn enqueue_task(&self, wtxn: &mut RwTxn, task: &Task) -> heed::Result<TaskKey> {
+        let key = TaskKey::new(task.priority, self.next_task_id(wtxn)?);
+        self.enqueued.put(wtxn, &key, task)?;
+        Ok(key)
+    }
+
+    /// Moves the tasks of the queue used before the tasks had a priority
+    /// into the current queue, keeping their order, returns their number.
+    pub fn migrate_legacy_tasks(&self, wtxn: &mut RwTxn) -> heed::Result<u64> {
+        let mut kinds = Vec::new();
+        for result in self.legacy_enqueued.iter(wtxn)? {
+            let (_, kind) = result?;
+            kinds.push(kind);
+        }
+        let count = kinds.len() as u64;
+        for kind in kinds {
+            self.enqueue_task(wtxn, &Task::new(Priority::default(), kind))?;
+        }
+        self.legacy_enqueued.clear(wtxn)?;
+        Ok(count)
+    }
+
+    /// Moves an enqueued task into the processing ones, a claimed task is not lost
+    /// on a crash. Only one indexer runs on a database, it requeues them when it starts.
+    pub fn claim_task(&self, wtxn: &mut RwTxn, key: TaskKey) -> heed::Result<Option<Task>> {
+        let task = self.enqueued.get(wtxn, &key)?;
+        if let Some(task) = &task {
+            self.enqueued.delete(wtxn, &key)?;
+            self.processing.put(wtxn, &key, task)?;
+            self.progress.put(wtxn, &key, &TaskProgress::new())?;
+        }
+        Ok(task)
+    }
+
+    /// Removes a processed task from the processing ones and adds its report
+    /// to the history, the oldest reports are removed from the history.
+    pub fn finish_task(
+        &self,
+        wtxn: &mut RwTxn,
+        key: TaskKey,
+        report: &TaskReport,
+    ) -> heed::Result<()> {
+        self.processing.delete(wtxn, &key)?;
+        self.progress.delete(wtxn, &key)?;
+        self.crawl_file_done(wtxn, &report.task, report.error.is_none())?;
+        let id = self.history.last(wtxn)?.map_or(0, |(id, _)| id + 1);
+        self.history.put(wtxn, &id, report)?;
+        if let Some(oldest) = id.checked_sub(Self::HISTORY_SIZE) {
+            self.history.delete_range(wtxn, &(..=oldest))?;
+        }
+        Ok(())
+    }
+
+    /// Puts back in the queue the tasks that were being processed when
+    /// the indexer stopped, returns the number of requeued tasks.
+    pub fn requeue_processing_tasks(&self, wtxn: &mut RwTxn) -> heed::Result<u64> {
+        let mut tasks = Vec::new();
+        for result in self.processing.iter(wtxn)? {
+            tasks.push(result?);
+        }
+        for (key, task) in &tasks {
+            self.enqueued.put(wtxn, key, task)?;
+        }
+        self.processing.clear(wtxn)?;
+        self.progress.clear(wtxn)?;
+        Ok(tasks.len() as u64)
+    }
+
+    /// Removes a task from the queue, returns whether it was still enqueued,
+    /// the tasks that are already being processed cannot be cancelled.
+    pub fn cancel_task(&self, wtxn: &mut RwTxn, key: TaskKey) -> heed::Result<bool> {
+        let Some(task) = self.enqueued.get(wtxn, &key)? else { return Ok(false) };
+        self.enqueued.delete(wtxn, &key)?;
+        // The crawl slice of a cancelled file is not entirely indexed
+        self.crawl_file_done(wtxn, &task, false)?;
+        Ok(true)
+    }
+
+    /// Moves an enqueued task before all the others and returns its new key, the task
+    /// takes the high priority and a new id. It returns `None` if the task is not enqueued.
+    pub fn move_task_to_front(
+        &self,
+        wtxn: &mut RwTxn,
+        key: TaskKey,
+    ) -> heed::Result<Option<TaskKey>> {
+        let Some(mut task) = self.enqueued.get(wtxn, &key)? else { return Ok(None) };
+        if self.enqueued.first(wtxn)?.is_some_and(|(first, _)| first == key) {
+            return Ok(Some(key));
+        }
+
+        // The tasks moved to the front are ordered by decreasing ids
+        let front = TaskKey::front(self.next_task_id(wtxn)?);
+        task.priority = front.priority;
+        self.enqueued.delete(wtxn, &key)?;
+        self.enqueued.put(wtxn, &front, &task)?;
+        Ok(Some(front))
+    }
+
+    /// Whether the same selection of the files of a crawl is already in the queue
+    /// or being processed, either its list or some of the files selected from it.
+    pub fn is_crawl_in_queue(&self, rtxn: &RoTxn, paths: &WarcPaths) -> heed::Result<bool> {
+        let Some(slice) = paths.slice() else { return Ok(false) };
+        if self.pending_crawl_slices(rtxn)?.contains_key(&slice) {
+            return Ok(true);
+        }
+        for result in self.processing.iter(rtxn)?.chain(self.enqueued.iter(rtxn)?) {
+            let (_, task) = result?;
+            match &task.kind {
+                TaskKind::WarcUrlPaths(other) if other.slice().as_ref() == Some(&slice) => {
+                    return Ok(true)
+                }
+                _ => (),
+            }
+        }
+        Ok(false)
+    }
+
+    /// The crawl slices whose files were all indexed successfully, see [`WarcPaths::slice`].
+    pub fn indexed_crawls(&self, rtxn: &RoTxn) -> heed::Result<BTreeSet<String>> {
+        let main = self.main.remap_types::<Str, SerdeJson<BTreeSet<String>>>();
+        main.get(rtxn, "indexed-crawls").map(Option::unwrap_or_default)
+    }
+
+    fn put_indexed_crawls(&self, wtxn: &mut RwTxn, crawls: &BTreeSet<String>) -> heed::Result<()> {
+        let main = self.main.remap_types::<Str, SerdeJson<BTreeSet<String>>>();
+        main.put(wtxn, "indexed-crawls", crawls)
+    }
+
+    /// The crawl slices whose files are enqueued or being processed.
+    fn pending_crawl_slices(
+        &self,
+        rtxn: &RoTxn,
+    ) -> heed::Result<BTreeMap<String, PendingCrawlSlice>> {
+        let main = self.main.remap_types::<Str, SerdeJson<BTreeMap<_, _>>>();
+        main.get(rtxn, "pending-crawl-slices").map(Option::unwrap_or_default)
+    }
+
+    fn put_pending_crawl_slices(
+        &self,
+        wtxn: &mut RwTxn,
+        slices: &BTreeMap<String, PendingCrawlSlice>,
+    ) -> heed::Result<()> {
+        let main = self.main.remap_types::<Str, SerdeJson<BTreeMap<_, _>>>();
+        main.put(wtxn, "pending-crawl-slices", slices)
+    }
+
+    /// Enqueues the files selected from the list of a crawl, the crawl slice
+    /// is recorded as indexed once all of them were indexed successfully.
+    pub fn enqueue_crawl_files(
+        &self,
+        wtxn: &mut RwTxn,
+        paths: &WarcPaths,
+        priority: Priority,
+        files: impl IntoIterator<Item = TaskKind>,
+    ) -> heed::Result<u64> {
+        let crawl_slice = paths.slice();
+        let mut count = 0;
+        for kind in files {
+            let task = Task { crawl_slice: crawl_slice.clone(), ..Task::new(priority, kind) };
+            self.enqueue_task(wtxn, &task)?;
+            count += 1;
+        }
+        if let Some(slice) = crawl_slice.filter(|_| count > 0) {
+            let mut slices = self.pending_crawl_slices(wtxn)?;
+            slices.insert(slice, PendingCrawlSlice { remaining: count, failed: false });
+            self.put_pending_crawl_slices(wtxn, &slices)?;
+        }
+        Ok(count)
+    }
+
+    /// Counts a file of a crawl slice as done, the slice is recorded as indexed
+    /// when it was the last one and none of the files failed or was cancelled.
+    fn crawl_file_done(&self, wtxn: &mut RwTxn, task: &Task, succeeded: bool) -> heed::Result<()> {
+        let Some(slice) = &task.crawl_slice else { return Ok(()) };
+        let mut slices = self.pending_crawl_slices(wtxn)?;
+        // The slice is forgotten when its crawl is cleared
+        let Some(pending) = slices.get_mut(slice) else { return Ok(()) };
+        pending.remaining = pending.remaining.saturating_sub(1);
+        pending.failed |= !succeeded;
+        if pending.remaining == 0 {
+            if !pending.failed {
+                let mut crawls = self.indexed_crawls(wtxn)?;
+                crawls.insert(slice.clone());
+                self.put_indexed_crawls(wtxn, &crawls)?;
+            }
+            slices.remove(slice);
+        }
+        self.put_pending_crawl_slices(wtxn, &slices)
+    }
+
+    /// Removes the enqueued tasks fetching the files of a crawl, e.g. `CC-MAIN-2023-23`,
+    /// and returns their number. The crawl is forgotten and can be registered again.
+    pub fn clear_crawl_tasks(&self, wtxn: &mut RwTxn, crawl_id: &str) -> heed::Result<u64> {
+        let crawl_path = format!("/crawl-data/{crawl_id}/");
+        let mut keys = Vec::new();
+        for result in self.enqueued.iter(wtxn)? {
+            let (key, task) = result?;
+            if task.kind.url().is_some_and(|url| url.path().contains(&crawl_path)) {
+                keys.push(key);
             }
         }
-        Ok(u32::MAX)
+        for key in &keys {
+            self.enqueued.delete(wtxn, key)?;
+        }
+
+        let crawl_dir = &crawl_path[1..];
+        let mut slices = self.pending_crawl_slices(wtxn)?;
+        slices.retain(|slice, _| !slice.starts_with(crawl_dir));
+        self.put_pending_crawl_slices(wtxn, &slices)?;
+        let mut crawls = self.indexed_crawls(wtxn)?;
+        crawls.retain(|slice| !slice.starts_with(crawl_dir));
+        self.put_indexed_crawls(wtxn, &crawls)?;
+
+        Ok(keys.len() as u64)
+    }
+
+    /// Whether the indexer must stop claiming new tasks, it finishes the current ones.
+    pub fn is_paused(&self, rtxn: &RoTxn) -> heed::Result<bool> {
+        let main = self.main.remap_types::<Str, Unit>();
+        Ok(main.get(rtxn, "indexer-paused")?.is_some())
+    }
+
+    pub fn set_paused(&self, wtxn: &mut RwTxn, paused: bool) -> heed::Result<()> {
+        let main = self.main.remap_types::<Str, Unit>();
+        if paused {
+            main.put(wtxn, "indexer-paused", &())
+        } else {
+            main.delete(wtxn, "indexer-paused").map(drop)
+        }
+    }
+
+    /// Reserves a block of search ids that were never given, the search server
+    /// reserves them before giving them so that a restart doesn't give them again.
+    pub fn reserve_search_ids(&self, wtxn: &mut RwTxn, count: u64) -> heed::Result<Range<u64>> {
+        let main = self.main.remap_types::<Str, U64<BE>>();
+        // The searches logged before the ids were reserved
+        let logged = self.searches.last(wtxn)?.map_or(0, |(id, _)| id + 1);
+        let start = main.get(wtxn, "next-search-id")?.unwrap_or_default().max(logged);
+        main.put(wtxn, "next-search-id", &(start + count))?;
+        Ok(start..start + count)
+    }
+
+    /// Logs a search under an id given by the search server, the links
+    /// of the results must refer to the search before it is written.
+    pub fn log_search(&self, wtxn: &mut RwTxn, id: u64, search: &SearchLog) -> heed::Result<()> {
+        self.searches.put(wtxn, &id, search)
+    }
+
+    /// Appends a click to the log with the query of its search, returns
+    /// `false` and ignores the click if the search was not logged.
+    pub fn log_click(&self, wtxn: &mut RwTxn, mut click: Click) -> heed::Result<bool> {
+        let Some(search) = self.searches.get(wtxn, &click.search)? else { return Ok(false) };
+        click.query = search.query;
+        let id = self.clicks.last(wtxn)?.map_or(0, |(id, _)| id + 1);
+        self.clicks.put(wtxn, &id, &click)?;
+        Ok(true)
+    }
+
+    /// Computes the click-through rate and mean reciprocal rank of the logged queries.
+    pub fn query_stats(&self, rtxn: &RoTxn) -> heed::Result<BTreeMap<String, QueryStats>> {
+        let searches = self.searches.iter(rtxn)?.collect::<heed::Result<Vec<_>>>()?;
+        let clicks = self.clicks.iter(rtxn)?.map(|r| r.map(|(_, click)| click));
+        let clicks = clicks.collect::<heed::Result<Vec<_>>>()?;
+        Ok(query_stats(searches, clicks))
+    }
+
+    /// Returns the secret key with this name, it is generated the first time.
+    pub fn secret(&self, wtxn: &mut RwTxn, name: &str) -> heed::Result<Vec<u8>> {
+        let main = self.main.remap_types::<Str, ByteSlice>();
+        let key = format!("secret-{name}");
+        if let Some(secret) = main.get(wtxn, &key)? {
+            return Ok(secret.to_vec());
+        }
+        let secret = generate_secret();
+        main.put(wtxn, &key, &secret)?;
+        Ok(secret.to_vec())
+    }
+
+    /// Creates an API token for an admin and returns it, it cannot be retrieved later.
+    pub fn create_api_token(&self, wtxn: &mut RwTxn, name: &str) -> heed::Result<String> {
+        let token = generate_api_token();
+        let api_token = ApiToken { name: name.to_owned(), created_at: unix_timestamp() };
+        self.api_tokens.put(wtxn, &api_token_digest(&token), &api_token)?;
+        Ok(token)
+    }
+
+    pub fn find_api_token(&self, rtxn: &RoTxn, token: &str) -> heed::Result<Option<ApiToken>> {
+        self.api_tokens.get(rtxn, &api_token_digest(token))
+    }
+
+    /// Removes all the API tokens of an admin, returns their number.
+    pub fn revoke_api_tokens(&self, wtxn: &mut RwTxn, name: &str) -> heed::Result<u64> {
+        let mut digests = Vec::new();
+        for result in self.api_tokens.iter(wtxn)? {
+            let (digest, api_token) = result?;
+            if api_token.name == name {
+                digests.push(digest.to_owned());
+            }
+        }
+        for digest in &digests {
+            self.api_tokens.delete(wtxn, digest)?;
+        }
+        Ok(digests.len() as u64)
+    }
+}
+
+/// The fixed-size key under which the docid of a URL is stored.
+fn uri_digest(uri: &str) -> [u8; digest::SHA256_OUTPUT_LEN] {
+    let mut key = [0; digest::SHA256_OUTPUT_LEN];
+    key.copy_from_slice(digest::digest(&digest::SHA256, uri.as_bytes()).as_ref());
+    key
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::available_docids_iter::AvailableDocIds;
+    use crate::task::PathsSelection;
+
+    fn open_database(dir: &Path) -> Database {
+        let mut options = EnvOpenOptions::new();
+        // The pages freed in a transaction are only reused by the next ones
+        options.map_size(100 * 1024 * 1024);
+        Database::open_or_create(options, dir).unwrap()
+    }
+
+    #[test]
+    fn compact_docids() {
+        let dir = tempfile::tempdir().unwrap();
+        let database = open_database(dir.path());
+
+        let mut wtxn = database.write_txn().unwrap();
+        for (docid, uri) in [(3, "https://a.com/"), (7, "https://b.com/"), (10, "https://c.com/")] {
+            database.docid_uri.put(&mut wtxn, &docid, uri).unwrap();
+            database.put_uri_docid(&mut wtxn, uri, docid).unwrap();
+            let metadata = DocumentMetadata { title: Some(uri.to_owned()), ..Default::default() };
+            database.docid_metadata.put(&mut wtxn, &docid, &metadata).unwrap();
+        }
+        let all_docids = RoaringTreemap::from_iter([3, 7, 10]);
+        database.put_all_docids(&mut wtxn, &all_docids).unwrap();
+        // The docids 1 and 12 are unknown, e.g. left by a crashed write
+        let postings = RoaringTreemap::from_iter([1, 7, 10, 12]);
+        database.title_ngrams_docids.put(&mut wtxn, "com", &postings).unwrap();
+        let postings = RoaringTreemap::from_iter([1]);
+        database.content_ngrams_docids.put(&mut wtxn, "cro", &postings).unwrap();
+        database.docid_uri.put(&mut wtxn, &12, "https://d.com/").unwrap();
+        database.put_uri_docid(&mut wtxn, "https://d.com/", 12).unwrap();
+        database.put_uri_docid(&mut wtxn, "https://e.com/", 1).unwrap();
+        database.docid_metadata.put(&mut wtxn, &12, &DocumentMetadata::default()).unwrap();
+        let outlinks = vec![Url::parse("https://d.com/").unwrap()];
+        database.docid_outlinks.put(&mut wtxn, &1, &outlinks).unwrap();
+        let deletion = Task::new(Priority::High, TaskKind::DeleteDocuments(vec![5, 10]));
+        let key = database.enqueue_task(&mut wtxn, &deletion).unwrap();
+
+        // The deletion being processed refers to the current docids
+        let processed = Task::new(Priority::Normal, TaskKind::DeleteDocuments(vec![3]));
+        let processed = database.enqueue_task(&mut wtxn, &processed).unwrap();
+        database.claim_task(&mut wtxn, processed).unwrap().unwrap();
+        assert_eq!(database.compact_docids(&mut wtxn).unwrap(), None);
+        assert_eq!(database.docid_uri.get(&wtxn, &10).unwrap(), Some("https://c.com/"));
+        database.processing.delete(&mut wtxn, &processed).unwrap();
+
+        assert_eq!(database.compact_docids(&mut wtxn).unwrap(), Some(3));
+
+        assert_eq!(database.all_docids(&wtxn).unwrap(), RoaringTreemap::from_iter(0..3));
+        assert_eq!(database.docid_uri.get(&wtxn, &1).unwrap(), Some("https://b.com/"));
+        assert_eq!(database.docid_uri.get(&wtxn, &7).unwrap(), None);
+        assert_eq!(database.uri_docid(&wtxn, "https://c.com/").unwrap(), Some(2));
+        let metadata = database.docid_metadata.get(&wtxn, &0).unwrap().unwrap();
+        assert_eq!(metadata.title.as_deref(), Some("https://a.com/"));
+        let postings = database.title_ngrams_docids.get(&wtxn, "com").unwrap().unwrap();
+        assert_eq!(postings, RoaringTreemap::from_iter([1, 2]));
+        assert_eq!(database.content_ngrams_docids.get(&wtxn, "cro").unwrap(), None);
+        let task = database.enqueued.get(&wtxn, &key).unwrap().unwrap();
+        assert!(matches!(task.kind, TaskKind::DeleteDocuments(docids) if docids == [2]));
+        // Nothing is left of the unknown docids for the renumbered documents to inherit
+        assert_eq!(database.docid_uri.len(&wtxn).unwrap(), 3);
+        assert_eq!(database.uri_digest_docid.len(&wtxn).unwrap(), 3);
+        assert_eq!(database.docid_metadata.len(&wtxn).unwrap(), 3);
+        assert_eq!(database.docid_outlinks.get(&wtxn, &1).unwrap(), None);
+
+        // The docids are already dense
+        assert_eq!(database.compact_docids(&mut wtxn).unwrap(), Some(0));
+    }
+
+    #[test]
+    fn delete_documents() {
+        let dir = tempfile::tempdir().unwrap();
+        let database = open_database(dir.path());
+
+        let mut wtxn = database.write_txn().unwrap();
+        for (docid, uri) in [(0, "https://a.com/"), (1, "https://b.com/"), (2, "https://c.com/")] {
+            database.docid_uri.put(&mut wtxn, &docid, uri).unwrap();
+            database.put_uri_docid(&mut wtxn, uri, docid).unwrap();
+            let metadata = DocumentMetadata { title: Some(uri.to_owned()), ..Default::default() };
+            database.docid_metadata.put(&mut wtxn, &docid, &metadata).unwrap();
+        }
+        database.put_all_docids(&mut wtxn, &RoaringTreemap::from_iter(0..3)).unwrap();
+        let postings = RoaringTreemap::from_iter([0, 1]);
+        database.title_ngrams_docids.put(&mut wtxn, "com", &postings).unwrap();
+        database.content_ngrams_docids.put(&mut wtxn, "cro", &postings).unwrap();
+        database
+            .content_ngrams_docids
+            .put(&mut wtxn, "bag", &RoaringTreemap::from_iter([1]))
+            .unwrap();
+
+        database.delete_documents(&mut wtxn, &RoaringTreemap::from_iter([1])).unwrap();
+
+        assert_eq!(database.all_docids(&wtxn).unwrap(), RoaringTreemap::from_iter([0, 2]));
+        assert_eq!(database.uri_docid(&wtxn, "https://b.com/").unwrap(), None);
+        assert_eq!(database.docid_uri.get(&wtxn, &1).unwrap(), None);
+        assert!(database.docid_metadata.get(&wtxn, &1).unwrap().is_none());
+        assert_eq!(database.uri_docid(&wtxn, "https://a.com/").unwrap(), Some(0));
+
+        // The searches ignore the deleted documents still in the postings
+        let deleted = database.deleted_docids(&wtxn).unwrap();
+        assert_eq!(deleted, RoaringTreemap::from_iter([1]));
+        let postings = database.title_ngrams_docids.get(&wtxn, "com").unwrap().unwrap();
+        assert_eq!(postings - &deleted, RoaringTreemap::from_iter([0]));
+
+        // The purge removes them from the postings and their docids can be reused
+        assert_eq!(database.purge_deleted_docids(&mut wtxn).unwrap(), 1);
+        assert!(database.deleted_docids(&wtxn).unwrap().is_empty());
+        let postings = database.title_ngrams_docids.get(&wtxn, "com").unwrap().unwrap();
+        assert_eq!(postings, RoaringTreemap::from_iter([0]));
+        let postings = database.content_ngrams_docids.get(&wtxn, "cro").unwrap().unwrap();
+        assert_eq!(postings, RoaringTreemap::from_iter([0]));
+        assert_eq!(database.content_ngrams_docids.get(&wtxn, "bag").unwrap(), None);
+        let mut available = AvailableDocIds::new(&database.all_docids(&wtxn).unwrap());
+        assert_eq!(available.next(), Some(1));
+        assert_eq!(database.purge_deleted_docids(&mut wtxn).unwrap(), 0);
+
+        // The compaction renumbers the documents after the hole
+        assert_eq!(database.compact_docids(&mut wtxn).unwrap(), Some(1));
+        assert_eq!(database.uri_docid(&wtxn, "https://c.com/").unwrap(), Some(1));
+
+        // The documents that were not indexed are ignored
+        database.delete_documents(&mut wtxn, &RoaringTreemap::from_iter([5])).unwrap();
+        assert!(database.deleted_docids(&wtxn).unwrap().is_empty());
+        assert_eq!(database.compact_docids(&mut wtxn).unwrap(), Some(0));
+    }
+
+    #[test]
+    fn claim_and_requeue_tasks() {
+        let dir = tempfile::tempdir().unwrap();
+        let database = open_database(dir.path());
+
+        let mut wtxn = database.write_txn().unwrap();
+        let deletion = |docid| TaskKind::DeleteDocuments(vec![docid]);
+        let first = database.enqueue_task(&mut wtxn, &Task::new(Priority::Normal, deletion(1)));
+        let first = first.unwrap();
+        database.enqueue_task(&mut wtxn, &Task::new(Priority::Low, deletion(2))).unwrap();
+        database.enqueue_task(&mut wtxn, &Task::new(Priority::High, deletion(3))).unwrap();
+        database.enqueue_task(&mut wtxn, &Task::new(Priority::Normal, deletion(4))).unwrap();
+
+        let order: Vec<_> = database
+            .enqueued
+            .iter(&wtxn)
+            .unwrap()
+            .map(|r| match r.unwrap().1.kind {
+                TaskKind::DeleteDocuments(docids) => docids[0],
+                _ => unreachable!(),
+            })
+            .collect();
+        assert_eq!(order, [3, 1, 4, 2]);
+
+        assert!(database.claim_task(&mut wtxn, first).unwrap().is_some());
+        assert!(database.claim_task(&mut wtxn, first).unwrap().is_none());
+        assert_eq!(database.enqueued.len(&wtxn).unwrap(), 3);
+        // The ids are never reused, even when the last task is removed
+        let key = database.enqueue_task(&mut wtxn, &Task::new(Priority::Low, deletion(5)));
+        assert_eq!(key.unwrap().id, 4);
+
+        assert_eq!(database.requeue_processing_tasks(&mut wtxn).unwrap(), 1);
+        assert!(database.processing.is_empty(&wtxn).unwrap());
+        let task = database.enqueued.get(&wtxn, &first).unwrap().unwrap();
+        assert!(matches!(task.kind, TaskKind::DeleteDocuments(docids) if docids == [1]));
+    }
+
+    #[test]
+    fn task_queue_actions() {
+        let dir = tempfile::tempdir().unwrap();
+        let database = open_database(dir.path());
+
+        let mut wtxn = database.write_txn().unwrap();
+        let url = |s: &str| Url::parse(&format!("https://data.commoncrawl.org/crawl-data/{s}"));
+        let warc = |s| TaskKind::WarcUrl(url(s).unwrap());
+        let mut enqueue =
+            |priority, kind| database.enqueue_task(&mut wtxn, &Task::new(priority, kind)).unwrap();
+        let done = enqueue(Priority::High, warc("CC-MAIN-2023-23/a.warc.gz"));
+        let processing = enqueue(Priority::High, warc("CC-MAIN-2023-14/b.warc.gz"));
+        let second = enqueue(Priority::High, warc("CC-MAIN-2023-14/c.warc.gz"));
+        let low = enqueue(Priority::Low, warc("CC-MAIN-2023-23/d.warc.gz"));
+        let deletion = enqueue(Priority::Normal, TaskKind::DeleteDocuments(vec![1]));
+
+        assert!(database.claim_task(&mut wtxn, done).unwrap().is_some());
+        database.processing.delete(&mut wtxn, &done).unwrap();
+        assert!(database.claim_task(&mut wtxn, processing).unwrap().is_some());
+        assert_eq!(database.move_task_to_front(&mut wtxn, processing).unwrap(), None);
+
+        // The moved task gets an id that was never used
+        let front = database.move_task_to_front(&mut wtxn, low).unwrap().unwrap();
+        assert_eq!(front, TaskKey::front(deletion.id + 1));
+        assert_eq!(front, database.enqueued.first(&wtxn).unwrap().unwrap().0);
+        assert_eq!(database.enqueued.get(&wtxn, &front).unwrap().unwrap().priority, Priority::High);
+        assert!(database.enqueued.get(&wtxn, &low).unwrap().is_none());
+        assert_eq!(database.move_task_to_front(&mut wtxn, front).unwrap(), Some(front));
+
+        // The last moved task comes first
+        let second = database.move_task_to_front(&mut wtxn, second).unwrap().unwrap();
+        assert_eq!(second, database.enqueued.first(&wtxn).unwrap().unwrap().0);
+        let front = database.move_task_to_front(&mut wtxn, front).unwrap().unwrap();
+        assert_eq!(front, TaskKey::front(deletion.id + 3));
+        assert_eq!(front, database.enqueued.first(&wtxn).unwrap().unwrap().0);
+
+        assert!(database.cancel_task(&mut wtxn, deletion).unwrap());
+        assert!(!database.cancel_task(&mut wtxn, deletion).unwrap());
+        assert!(!database.cancel_task(&mut wtxn, processing).unwrap());
+
+        assert_eq!(database.clear_crawl_tasks(&mut wtxn, "CC-MAIN-2023-23").unwrap(), 1);
+        let keys: Vec<_> = database.enqueued.iter(&wtxn).unwrap().map(|r| r.unwrap().0).collect();
+        assert_eq!(keys, [second]);
+
+        assert!(!database.is_paused(&wtxn).unwrap());
+        database.set_paused(&mut wtxn, true).unwrap();
+        assert!(database.is_paused(&wtxn).unwrap());
+        database.set_paused(&mut wtxn, false).unwrap();
+        assert!(!database.is_paused(&wtxn).unwrap());
+    }
+
+    #[test]
+    fn finish_tasks() {
+        let dir = tempfile::tempdir().unwrap();
+        let database = open_database(dir.path());
+
+        let mut wtxn = database.write_txn().unwrap();
+        let deletion = |docid| Task::new(Priority::Normal, TaskKind::DeleteDocuments(vec![docid]));
+        for docid in 0..=Database::HISTORY_SIZE {
+            let key = database.enqueue_task(&mut wtxn, &deletion(docid)).unwrap();
+            let task = database.claim_task(&mut wtxn, key).unwrap().unwrap();
+            let progress = database.progress.get(&wtxn, &key).unwrap().unwrap();
+            assert_eq!(progress.stage, None);
+
+            let report = TaskReport::new(task, progress);
+            database.finish_task(&mut wtxn, key, &report).unwrap();
+            assert!(database.processing.get(&wtxn, &key).unwrap().is_none());
+            assert!(database.progress.get(&wtxn, &key).unwrap().is_none());
+        }
+
+        // The oldest report was removed
+        assert_eq!(database.history.len(&wtxn).unwrap(), Database::HISTORY_SIZE);
+        let (id, report) = database.history.first(&wtxn).unwrap().unwrap();
+        assert_eq!(id, 1);
+        assert!(matches!(report.task.kind, TaskKind::DeleteDocuments(docids) if docids == [1]));
+
+        // A requeued task starts again from scratch
+        let key = database.enqueue_task(&mut wtxn, &deletion(0)).unwrap();
+        database.claim_task(&mut wtxn, key).unwrap();
+        assert_eq!(database.requeue_processing_tasks(&mut wtxn).unwrap(), 1);
+        assert!(database.progress.is_empty(&wtxn).unwrap());
+    }
+
+    #[test]
+    fn crawls_in_queue() {
+        let dir = tempfile::tempdir().unwrap();
+        let database = open_database(dir.path());
+
+        let mut wtxn = database.write_txn().unwrap();
+        let url = |s: &str| Url::parse(&format!("https://data.commoncrawl.org/crawl-data/{s}"));
+        let slice = |s, range| {
+            let selection = PathsSelection { range, ..Default::default() };
+            WarcPaths::new(url(s).unwrap(), selection)
+        };
+        let warc_paths = slice("CC-MAIN-2023-23/warc.paths.gz", None);
+        let first = slice("CC-MAIN-2023-23/warc.paths.gz", Some(0..10));
+        let second = slice("CC-MAIN-2023-23/warc.paths.gz", Some(10..20));
+        let wet_paths = slice("CC-MAIN-2023-23/wet.paths.gz", None);
+        assert!(!database.is_crawl_in_queue(&wtxn, &warc_paths).unwrap());
+
+        let task = Task::new(Priority::Low, TaskKind::WarcUrlPaths(first));
+        let key = database.enqueue_task(&mut wtxn, &task).unwrap();
+        let first = slice("CC-MAIN-2023-23/warc.paths.gz", Some(0..10));
+        assert!(database.is_crawl_in_queue(&wtxn, &first).unwrap());
+        assert!(!database.is_crawl_in_queue(&wtxn, &second).unwrap());
+        assert!(!database.is_crawl_in_queue(&wtxn, &warc_paths).unwrap());
+        assert!(!database.is_crawl_in_queue(&wtxn, &wet_paths).unwrap());
+
+        // The list was expanded into the files of the slice
+        database.claim_task(&mut wtxn, key).unwrap();
+        let file = |s: &str| TaskKind::WarcUrl(url(s).unwrap());
+        let files = [file("CC-MAIN-2023-23/a.warc.gz"), file("CC-MAIN-2023-23/b.warc.gz")];
+        assert_eq!(
+            database.enqueue_crawl_files(&mut wtxn, &first, Priority::Low, files).unwrap(),
+            2
+        );
+        let report = TaskReport::new(task, TaskProgress::new());
+        database.finish_task(&mut wtxn, key, &report).unwrap();
+        assert!(database.is_crawl_in_queue(&wtxn, &first).unwrap());
+        assert!(!database.is_crawl_in_queue(&wtxn, &second).unwrap());
+
+        // The slice is only indexed once all its files were indexed
+        let index_next_file = |wtxn: &mut RwTxn, error: Option<&str>| {
+            let (key, _) = database.enqueued.first(wtxn).unwrap().unwrap();
+            let task = database.claim_task(wtxn, key).unwrap().unwrap();
+            let report = match error {
+                Some(error) => TaskReport::failed(task, TaskProgress::new(), error.to_owned()),
+                None => TaskReport::new(task, TaskProgress::new()),
+            };
+            database.finish_task(wtxn, key, &report).unwrap();
+        };
+        index_next_file(&mut wtxn, None);
+        assert!(database.indexed_crawls(&wtxn).unwrap().is_empty());
+        index_next_file(&mut wtxn, None);
+        let crawls = database.indexed_crawls(&wtxn).unwrap();
+        assert_eq!(crawls, BTreeSet::from([first.slice().unwrap()]));
+        assert!(!database.is_crawl_in_queue(&wtxn, &first).unwrap());
+
+        // A slice with a failed or cancelled file is not indexed
+        let files = [file("CC-MAIN-2023-23/c.warc.gz"), file("CC-MAIN-2023-23/d.warc.gz")];
+        database.enqueue_crawl_files(&mut wtxn, &second, Priority::Low, files).unwrap();
+        index_next_file(&mut wtxn, Some("No such file"));
+        let (key, _) = database.enqueued.first(&wtxn).unwrap().unwrap();
+        assert!(database.cancel_task(&mut wtxn, key).unwrap());
+        assert!(!database.is_crawl_in_queue(&wtxn, &second).unwrap());
+        assert!(!database.indexed_crawls(&wtxn).unwrap().contains(&second.slice().unwrap()));
+
+        // A cleared crawl can be registered again
+        let files = [file("CC-MAIN-2023-23/e.warc.gz")];
+        database.enqueue_crawl_files(&mut wtxn, &warc_paths, Priority::Low, files).unwrap();
+        assert!(database.is_crawl_in_queue(&wtxn, &warc_paths).unwrap());
+        assert_eq!(database.clear_crawl_tasks(&mut wtxn, "CC-MAIN-2023-23").unwrap(), 1);
+        assert!(!database.is_crawl_in_queue(&wtxn, &warc_paths).unwrap());
+        assert!(database.indexed_crawls(&wtxn).unwrap().is_empty());
+    }
+
+    #[test]
+    fn api_tokens() {
+        let dir = tempfile::tempdir().unwrap();
+        let database = open_database(dir.path());
+
+        let mut wtxn = database.write_txn().unwrap();
+        let first = database.create_api_token(&mut wtxn, "kero").unwrap();
+        let second = database.create_api_token(&mut wtxn, "kero").unwrap();
+        let other = database.create_api_token(&mut wtxn, "loic").unwrap();
+        assert_ne!(first, second);
+        let api_token = database.find_api_token(&wtxn, &first).unwrap().unwrap();
+        assert_eq!(api_token.name, "kero");
+        assert!(database.find_api_token(&wtxn, "croissant").unwrap().is_none());
+
+        assert_eq!(database.revoke_api_tokens(&mut wtxn, "kero").unwrap(), 2);
+        assert!(database.find_api_token(&wtxn, &second).unwrap().is_none());
+        assert!(database.find_api_token(&wtxn, &other).unwrap().is_some());
+
+        let secret = database.secret(&mut wtxn, "redirect").unwrap();
+        assert_eq!(database.secret(&mut wtxn, "redirect").unwrap(), secret);
+        assert_ne!(database.secret(&mut wtxn, "other").unwrap(), secret);
+    }
+
+    #[test]
+    fn clicks_log() {
+        let dir = tempfile::tempdir().unwrap();
+        let database = open_database(dir.path());
+
+        let mut wtxn = database.write_txn().unwrap();
+        for (docid, uri) in [(3, "https://a.com/"), (7, "https://b.com/"), (10, "https://c.com/")] {
+            database.docid_uri.put(&mut wtxn, &docid, uri).unwrap();
+            database.put_uri_docid(&mut wtxn, uri, docid).unwrap();
+        }
+        database.put_all_docids(&mut wtxn, &RoaringTreemap::from_iter([3, 7, 10])).unwrap();
+
+        let session = "session".to_owned();
+        let search =
+            SearchLog { query: "croissant".to_owned(), session: session.clone(), timestamp: 0 };
+        assert_eq!(database.reserve_search_ids(&mut wtxn, 10).unwrap(), 0..10);
+        database.log_search(&mut wtxn, 0, &search).unwrap();
+        database.log_search(&mut wtxn, 1, &search).unwrap();
+        // The ids of a block are never given again, even the unused ones
+        assert_eq!(database.reserve_search_ids(&mut wtxn, 10).unwrap(), 10..20);
+        let click = |search, position, docid| Click {
+            search,
+            query: String::new(),
+            position,
+            docid: Some(docid),
+            session: session.clone(),
+            timestamp: 0,
+        };
+        for (position, docid) in [(0, 3), (1, 7), (2, 10)] {
+            assert!(database.log_click(&mut wtxn, click(0, position, docid)).unwrap());
+        }
+        // The clicks of unknown searches are ignored
+        assert!(!database.log_click(&mut wtxn, click(2, 0, 3)).unwrap());
+        let (_, logged) = database.clicks.first(&wtxn).unwrap().unwrap();
+        assert_eq!(logged.query, "croissant");
+
+        let stats = database.query_stats(&wtxn).unwrap();
+        assert_eq!(stats["croissant"].searches, 2);
+        assert_eq!(stats["croissant"].clicks, 3);
+        assert_eq!(stats["croissant"].mean_reciprocal_rank(), 0.5);
+
+        // The clicks follow the documents when they are deleted or renumbered
+        database.delete_documents(&mut wtxn, &RoaringTreemap::from_iter([7])).unwrap();
+        database.compact_docids(&mut wtxn).unwrap();
+        let docids: Vec<_> =
+            database.clicks.iter(&wtxn).unwrap().map(|r| r.unwrap().1.docid).collect();
+        assert_eq!(docids, [Some(0), None, Some(1)]);
+    }
+
+    #[test]
+    fn migrate_legacy_tasks() {
+        let dir = tempfile::tempdir().unwrap();
+        let database = open_database(dir.path());
+
+        let mut wtxn = database.write_txn().unwrap();
+        // The lists of paths were given decreasing ids from the last one and the files
+        // increasing ids from zero, the tasks only contained the URL without a selection
+        let paths = "https://data.commoncrawl.org/crawl-data/CC-MAIN-2023-23/warc.paths.gz";
+        let file = "https://data.commoncrawl.org/crawl-data/CC-MAIN-2023-23/a.warc.gz";
+        let legacy = database.legacy_enqueued.remap_data_type::<Str>();
+        legacy.put(&mut wtxn, &u32::MAX, &format!(r#"{{"WarcUrlPaths":"{paths}"}}"#)).unwrap();
+        legacy.put(&mut wtxn, &0, &format!(r#"{{"WarcUrl":"{file}"}}"#)).unwrap();
+
+        assert_eq!(database.migrate_legacy_tasks(&mut wtxn).unwrap(), 2);
+        assert!(database.legacy_enqueued.is_empty(&wtxn).unwrap());
+        let tasks: Vec<_> = database.enqueued.iter(&wtxn).unwrap().map(|r| r.unwrap()).collect();
+        assert_eq!(tasks.len(), 2);
+        assert_eq!(tasks[0].0, TaskKey::new(Priority::Normal, 0));
+        assert!(matches!(&tasks[0].1.kind, TaskKind::WarcUrl(url) if url.as_str() == file));
+        assert_eq!(tasks[1].0, TaskKey::new(Priority::Normal, 1));
+        assert!(matches!(
+            &tasks[1].1.kind,
+            TaskKind::WarcUrlPaths(p) if p.url.as_str() == paths && p.selection.is_everything()
+        ));
     }
 }
@@ -0,0 +1,67 @@
+use serde::{Deserialize, Serialize};
+
+/// The metadata we store about every indexed document.
+#[derive(Debug, Default, Clone, Deserialize, Serialize)]
+pub struct DocumentMetadata {
+    pub title: Option<String>,
+    /// The language declared by the page, e.g. `fr` or `en-US`.
+    pub lang: Option<String>,
+    /// The content of the `<meta name="description">` tag.
+    pub description: Option<String>,
+    /// The date at which the page was crawled, the `WARC-Date` header.
+    pub date: Option<String>,
+    /// The number of characters of the extracted text.
+    pub text_length: Option<u64>,
+    /// The SimHash fingerprint of the text, used to detect near-duplicates.
+    pub simhash: Option<u64>,
+}
+
+/// Decides which version of a document to keep when the same URL is indexed twice.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum DuplicatePolicy {
+    /// Keeps the most recently crawled version.
+    #[default]
+    Newest,
+    /// Keeps the version with the longest extracted text.
+    Longest,
+}
+
+impl DuplicatePolicy {
+    /// Returns `true` if the new version of a document must replace the old one,
+    /// the same version indexed again doesn't replace it.
+    pub fn prefers(&self, new: &DocumentMetadata, old: &DocumentMetadata) -> bool {
+        match self {
+            // The WARC dates are ISO 8601, they can be compared lexicographically
+            DuplicatePolicy::Newest => new.date > old.date,
+            DuplicatePolicy::Longest => new.text_length > old.text_length,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn duplicate_policies() {
+        let old = DocumentMetadata {
+            date: Some(String::from("2023-05-27T22:35:15Z")),
+            text_length: Some(1200),
+            ..Default::default()
+        };
+        let new = DocumentMetadata {
+            date: Some(String::from("2023-10-02T08:12:01Z")),
+            text_length: Some(800),
+            ..Default::default()
+        };
+
+        assert!(DuplicatePolicy::Newest.prefers(&new, &old));
+        assert!(!DuplicatePolicy::Newest.prefers(&old, &new));
+        assert!(!DuplicatePolicy::Longest.prefers(&new, &old));
+        assert!(DuplicatePolicy::Longest.prefers(&old, &new));
+        assert!(!DuplicatePolicy::Newest.prefers(&new, &new));
+        assert!(!DuplicatePolicy::Longest.prefers(&new, &new));
+        // Documents indexed before we stored the dates are always replaced
+        assert!(DuplicatePolicy::Newest.prefers(&new, &DocumentMetadata::default()));
+    }
+}
@@ -0,0 +1,380 @@
+use std::borrow::Cow;
+use std::io::{self, Read};
+use std::ops::RangeInclusive;
+use std::{error, fmt};
+
+use flate2::read::{DeflateDecoder, MultiGzDecoder, ZlibDecoder};
+use httparse::{Status, EMPTY_HEADER};
+
+/// The maximum number of bytes of a decompressed body, protects against zip bombs.
+const MAX_DECODED_BODY_LENGTH: u64 = 32 * 1024 * 1024; // 32 MiB
+
+/// The head of an HTTP response stored in a WARC record.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ResponseHead<'a> {
+    pub status: u16,
+    pub content_type: Option<&'a str>,
+    pub content_encoding: Option<&'a str>,
+    pub transfer_encoding: Option<&'a str>,
+    /// The number of bytes of the head, where the body starts.
+    pub length: usize,
+}
+
+impl<'a> ResponseHead<'a> {
+    /// Parses the head of an HTTP response.
+    pub fn parse(bytes: &'a [u8]) -> Result<ResponseHead<'a>, HttpError> {
+        let mut headers = [EMPTY_HEADER; 64];
+        let mut response = httparse::Response::new(&mut headers);
+        let length = match response.parse(bytes) {
+            Ok(Status::Complete(length)) => length,
+            Ok(Status::Partial) => return Err(HttpError::IncompleteHead),
+            Err(e) => return Err(HttpError::InvalidHead(e)),
+        };
+
+        let header = |name: &str| {
+            response
+                .headers
+                .iter()
+                .find(|h| h.name.eq_ignore_ascii_case(name))
+                .and_then(|h| std::str::from_utf8(h.value).ok())
+        };
+
+        Ok(ResponseHead {
+            status: response.code.unwrap_or_default(),
+            content_type: header("content-type"),
+            content_encoding: header("content-encoding"),
+            transfer_encoding: header("transfer-encoding"),
+            length,
+        })
+    }
+
+    /// Decodes the body according to the `Transfer-Encoding` and `Content-Encoding`
+    /// headers. Common Crawl renames these headers `X-Crawler-*` when it already
+    /// decoded the body, we therefore don't decode those bodies twice.
+    pub fn decode_body<'b>(&self, body: &'b [u8]) -> Result<Cow<'b, [u8]>, HttpError> {
+        // The codings are listed in the order they were applied
+        let transfer_codings = self.transfer_encoding.into_iter().flat_map(|te| te.split(','));
+        let content_codings = self.content_encoding.into_iter().flat_map(|ce| ce.split(','));
+        let mut codings: Vec<_> = content_codings.chain(transfer_codings).collect();
+
+        let mut body = Cow::Borrowed(body);
+        while let Some(coding) = codings.pop() {
+            let coding = coding.trim().to_ascii_lowercase();
+            body = match coding.as_str() {
+                "" | "identity" => body,
+                "chunked" => Cow::Owned(dechunk(&body)?),
+                "gzip" | "x-gzip" => Cow::Owned(decompress(MultiGzDecoder::new(&body[..]))?),
+                // Some servers send raw deflate streams instead of zlib ones
+                "deflate" => match decompress(ZlibDecoder::new(&body[..])) {
+                    Ok(decoded) => Cow::Owned(decoded),
+                    Err(_) => Cow::Owned(decompress(DeflateDecoder::new(&body[..]))?),
+                },
+                "br" => Cow::Owned(decompress(brotli::Decompressor::new(&body[..], 4096))?),
+                _ => return Err(HttpError::UnsupportedEncoding(coding)),
+            };
+        }
+
+        Ok(body)
+    }
+}
+
+/// Removes the chunked transfer coding of a body, truncated
+/// bodies are accepted as WARC records can be truncated.
+fn dechunk(mut body: &[u8]) -> Result<Vec<u8>, HttpError> {
+    let mut output = Vec::with_capacity(body.len());
+    loop {
+        let (offset, size) = match httparse::parse_chunk_size(body) {
+            Ok(Status::Complete((offset, size))) => (offset, size),
+            Ok(Status::Partial) => break,
+            Err(_) => return Err(HttpError::InvalidChunks),
+        };
+
+        if size == 0 {
+            break;
+        }
+
+        let chunk = &body[offset..];
+        match usize::try_from(size).ok().and_then(|size| chunk.get(..size)) {
+            Some(data) => {
+                output.extend_from_slice(data);
+                body = &chunk[data.len()..];
+                body = body.strip_prefix(b"\r\n").unwrap_or(body);
+            }
+            None => {
+                output.extend_from_slice(chunk);
+                break;
+            }
+        }
+    }
+    Ok(output)
+}
+
+/// Reads the whole decompressed stream, truncated streams are accepted
+/// as long as some bytes could be decompressed.
+fn decompress(reader: impl Read) -> Result<Vec<u8>, HttpError> {
+    let mut output = Vec::new();
+    match reader.take(MAX_DECODED_BODY_LENGTH).read_to_end(&mut output) {
+        Ok(_) => Ok(output),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof && !output.is_empty() => Ok(output),
+        Err(e) => Err(HttpError::Decompression(e)),
+    }
+}
+
+/// The reasons why an HTTP response can't be read.
+#[derive(Debug)]
+pub enum HttpError {
+    InvalidHead(httparse::Error),
+    IncompleteHead,
+    InvalidChunks,
+    UnsupportedEncoding(String),
+    Decompression(io::Error),
+}
+
+impl fmt::Display for HttpError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidHead(e) => write!(f, "invalid HTTP head: {e}"),
+            Self::IncompleteHead => f.write_str("incomplete HTTP head"),
+            Self::InvalidChunks => f.write_str("invalid chunked body"),
+            Self::UnsupportedEncoding(coding) => write!(f, "unsupported encoding {coding:?}"),
+            Self::Decompression(e) => write!(f, "invalid compressed body: {e}"),
+        }
+    }
+}
+
+impl error::Error for HttpError {}
+
+/// Decides which HTTP responses found in the WARC files are worth indexing.
+#[derive(Debug, Clone)]
+pub struct InclusionPolicy {
+    /// The accepted status codes.
+    pub statuses: Vec<RangeInclusive<u16>>,
+    /// The accepted mime types, lowercased and without parameters.
+    pub content_types: Vec<String>,
+}
+
+impl Default for InclusionPolicy {
+    /// Only the successful HTML responses are accepted.
+    fn default() -> Self {
+        InclusionPolicy {
+            statuses: vec![200..=299],
+            content_types: vec![String::from("text/html"), String::from("application/xhtml+xml")],
+        }
+    }
+}
+
+impl InclusionPolicy {
+    /// Returns the reason why a response must be skipped, if any.
+    pub fn check(&self, status: u16, content_type: Option<&str>) -> Result<(), SkipReason> {
+        if !self.statuses.iter().any(|range| range.contains(&status)) {
+            return Err(SkipReason::Status(status));
+        }
+
+        match content_type.map(mime_type) {
+            Some(mime) if self.content_types.contains(&mime) => Ok(()),
+            Some(mime) => Err(SkipReason::ContentType(mime)),
+            None => Err(SkipReason::MissingContentType),
+        }
+    }
+}
+
+/// The reason why an HTTP response has not been indexed.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum SkipReason {
+    Status(u16),
+    ContentType(String),
+    MissingContentType,
+}
+
+impl fmt::Display for SkipReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Status(status) => write!(f, "status {status}"),
+            Self::ContentType(mime) => write!(f, "content type {mime}"),
+            Self::MissingContentType => f.write_str("missing content type"),
+        }
+    }
+}
+
+/// Returns the lowercased mime type of a `Content-Type` header value,
+/// i.e. `text/html` for `Text/HTML; charset=UTF-8`.
+pub fn mime_type(content_type: &str) -> String {
+    let mime = content_type.split(';').next().unwrap_or_default();
+    mime.trim().to_ascii_lowercase()
+}
+
+/// Parses status codes ranges like `2xx`, `200-299` or `404`,
+/// the classes go from `1xx` to `5xx` and the ranges cannot be empty.
+pub fn parse_status_range(s: &str) -> Result<RangeInclusive<u16>, String> {
+    let parse = |s: &str| s.trim().parse::<u16>().map_err(|e| format!("invalid status `{s}`: {e}"));
+    match (s.strip_suffix("xx"), s.split_once('-')) {
+        (Some(class), _) => {
+            let class = parse(class)?;
+            let start = class.checked_mul(100);
+            match start.zip(start.and_then(|start| start.checked_add(99))) {
+                Some((start, end)) if (1..=5).contains(&class) => Ok(start..=end),
+                _ => Err(format!("invalid status class `{s}`, it must be between 1xx and 5xx")),
+            }
+        }
+        (None, Some((start, end))) => match (parse(start)?, parse(end)?) {
+            (start, end) if start > end => Err(format!("the status range `{s}` is empty")),
+            (start, end) => Ok(start..=end),
+        },
+        (None, None) => parse(s).map(|status| status..=status),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use flate2::write::{GzEncoder, ZlibEncoder};
+    use flate2::Compression;
+
+    use super::*;
+
+    #[test]
+    fn default_policy() {
+        let policy = InclusionPolicy::default();
+        assert_eq!(policy.check(200, Some("text/html; charset=utf-8")), Ok(()));
+        assert_eq!(policy.check(204, Some("Application/XHTML+XML")), Ok(()));
+        assert_eq!(policy.check(404, Some("text/html")), Err(SkipReason::Status(404)));
+        assert_eq!(policy.check(301, None), Err(SkipReason::Status(301)));
+        assert_eq!(
+            policy.check(200, Some("image/png")),
+            Err(SkipReason::ContentType(String::from("image/png")))
+        );
+        assert_eq!(policy.check(200, None), Err(SkipReason::MissingContentType));
+    }
+
+    /// Wraps an HTTP response into a WARC record and reads it back.
+    fn warc_record(head: &str, body: &[u8]) -> warc::Record<warc::BufferedBody> {
+        let mut http = head.replace('\n', "\r\n").into_bytes();
+        http.extend_from_slice(b"\r\n");
+        http.extend_from_slice(body);
+
+        let mut record = format!(
+            "WARC/1.0\r\n\
+             WARC-Type: response\r\n\
+             WARC-Date: 2023-05-27T22:35:15Z\r\n\
+             WARC-Record-ID: <urn:uuid:0e8fbd56-6a5b-4e38-8d4c-6d9d06d3a4b2>\r\n\
+             WARC-Target-URI: https://boulangerie.fr/\r\n\
+             Content-Type: application/http; msgtype=response\r\n\
+             Content-Length: {}\r\n\
+             \r\n",
+            http.len()
+        )
+        .into_bytes();
+        record.extend_from_slice(&http);
+        record.extend_from_slice(b"\r\n\r\n");
+
+        let mut records = warc::WarcReader::new(&record[..]).iter_records();
+        records.next().unwrap().unwrap()
+    }
+
+    fn decode(record: &warc::Record<warc::BufferedBody>) -> Result<Vec<u8>, HttpError> {
+        let http = record.body();
+        let head = ResponseHead::parse(http)?;
+        head.decode_body(&http[head.length..]).map(Cow::into_owned)
+    }
+
+    const HTML: &[u8] = b"<html><body>Le croissant au beurre</body></html>";
+
+    #[test]
+    fn identity_body() {
+        let record = warc_record("HTTP/1.1 200 OK\nContent-Type: text/html\n", HTML);
+        let head = ResponseHead::parse(record.body()).unwrap();
+        assert_eq!(head.status, 200);
+        assert_eq!(head.content_type, Some("text/html"));
+        assert_eq!(decode(&record).unwrap(), HTML);
+    }
+
+    #[test]
+    fn chunked_body() {
+        let body = b"7\r\n<html><\r\n29\r\nbody>Le croissant au beurre</body></html>\r\n0\r\n\r\n";
+        let record = warc_record("HTTP/1.1 200 OK\nTransfer-Encoding: chunked\n", body);
+        assert_eq!(decode(&record).unwrap(), HTML);
+
+        // The record has been truncated in the middle of a chunk
+        let record = warc_record("HTTP/1.1 200 OK\nTransfer-Encoding: chunked\n", &body[..20]);
+        assert_eq!(decode(&record).unwrap(), &HTML[..11]);
+    }
+
+    #[test]
+    fn gzip_body() {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(HTML).unwrap();
+        let body = encoder.finish().unwrap();
+        let record = warc_record("HTTP/1.1 200 OK\nContent-Encoding: gzip\n", &body);
+        assert_eq!(decode(&record).unwrap(), HTML);
+    }
+
+    #[test]
+    fn deflate_body() {
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(HTML).unwrap();
+        let body = encoder.finish().unwrap();
+        let record = warc_record("HTTP/1.1 200 OK\nContent-Encoding: deflate\n", &body);
+        assert_eq!(decode(&record).unwrap(), HTML);
+    }
+
+    #[test]
+    fn brotli_body() {
+        let mut encoder = brotli::CompressorWriter::new(Vec::new(), 4096, 5, 22);
+        encoder.write_all(HTML).unwrap();
+        let body = encoder.into_inner();
+        let record = warc_record("HTTP/1.1 200 OK\nContent-Encoding: br\n", &body);
+        assert_eq!(decode(&record).unwrap(), HTML);
+    }
+
+    #[test]
+    fn chunked_gzip_body() {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(HTML).unwrap();
+        let gzipped = encoder.finish().unwrap();
+        let (first, second) = gzipped.split_at(10);
+        let mut body = Vec::new();
+        for chunk in [first, second] {
+            write!(&mut body, "{:x}\r\n", chunk.len()).unwrap();
+            body.extend_from_slice(chunk);
+            body.extend_from_slice(b"\r\n");
+        }
+        body.extend_from_slice(b"0\r\n\r\n");
+
+        let head = "HTTP/1.1 200 OK\nContent-Encoding: gzip\nTransfer-Encoding: chunked\n";
+        let record = warc_record(head, &body);
+        assert_eq!(decode(&record).unwrap(), HTML);
+    }
+
+    #[test]
+    fn already_decoded_body() {
+        let head = "HTTP/1.1 200 OK\nX-Crawler-Content-Encoding: gzip\nContent-Length: 48\n";
+        let record = warc_record(head, HTML);
+        assert_eq!(decode(&record).unwrap(), HTML);
+    }
+
+    #[test]
+    fn unsupported_body() {
+        let record = warc_record("HTTP/1.1 200 OK\nContent-Encoding: zstd\n", HTML);
+        assert!(matches!(decode(&record), Err(HttpError::UnsupportedEncoding(_))));
+    }
+
+    #[test]
+    fn status_ranges() {
+        assert_eq!(parse_status_range("2xx"), Ok(200..=299));
+        assert_eq!(parse_status_range("200-399"), Ok(200..=399));
+        assert_eq!(parse_status_range("404"), Ok(404..=404));
+        assert!(parse_status_range("abc").is_err());
+        assert_eq!(parse_status_range("1xx"), Ok(100..=199));
+        assert_eq!(parse_status_range("5xx"), Ok(500..=599));
+        assert!(parse_status_range("6xx").is_err());
+        assert!(parse_status_range("0xx").is_err());
+        assert!(parse_status_range("700xx").is_err());
+        assert!(parse_status_range("65535xx").is_err());
+        assert_eq!(parse_status_range("200-200"), Ok(200..=200));
+        assert_eq!(
+            parse_status_range("500-200"),
+            Err(String::from("the status range `500-200` is empty"))
+        );
+    }
+}
@@ -1,8 +1,19 @@
+pub mod auth;
 pub mod available_docids_iter;
+pub mod cdx;
+pub mod charset;
+pub mod clicks;
 pub mod database;
+pub mod document;
+pub mod http;
+pub mod progress;
+pub mod source;
 pub mod task;
+pub mod task_key_codec;
 pub mod text;
 pub mod treemap_codec;
+pub mod wakeup;
+pub mod wat;
 
 pub const DATABASE_MAX_SIZE: usize = 900 * 1024 * 1024 * 1024; // 900 GiB
 
@@ -0,0 +1,90 @@
+//! The progress of the tasks the indexer is processing and the history
+//! of the tasks it processed, both are displayed on the indexer page.
+
+use std::fmt;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::task::Task;
+
+/// The steps a task goes through, they are timed separately.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub enum Stage {
+    Downloading,
+    Extracting,
+    Writing,
+}
+
+impl fmt::Display for Stage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Stage::Downloading => f.write_str("downloading"),
+            Stage::Extracting => f.write_str("extracting"),
+            Stage::Writing => f.write_str("writing"),
+        }
+    }
+}
+
+/// What the indexer did so far on a task, it is regularly saved while processing it.
+#[derive(Debug, Default, Clone, PartialEq, Deserialize, Serialize)]
+pub struct TaskProgress {
+    /// When the task was claimed, in seconds since the Unix epoch.
+    pub started_at: u64,
+    /// The stage the task is in, none once it is done.
+    pub stage: Option<Stage>,
+    pub bytes_downloaded: u64,
+    pub records_processed: u64,
+    pub documents_indexed: u64,
+    /// The time spent in the stages that are over, in order.
+    pub timings: Vec<(Stage, Duration)>,
+}
+
+impl TaskProgress {
+    pub fn new() -> TaskProgress {
+        TaskProgress { started_at: unix_timestamp(), ..TaskProgress::default() }
+    }
+
+    /// The number of seconds since the task was claimed.
+    pub fn elapsed_secs(&self) -> u64 {
+        unix_timestamp().saturating_sub(self.started_at)
+    }
+}
+
+impl fmt::Display for TaskProgress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let TaskProgress { bytes_downloaded, records_processed, documents_indexed, .. } = self;
+        write!(f, "{bytes_downloaded} bytes, {records_processed} records, ")?;
+        write!(f, "{documents_indexed} documents")?;
+        for (stage, duration) in &self.timings {
+            write!(f, ", {stage} in {duration:.02?}")?;
+        }
+        Ok(())
+    }
+}
+
+/// A task the indexer is done with and what it took.
+#[derive(Deserialize, Serialize)]
+pub struct TaskReport {
+    pub task: Task,
+    pub progress: TaskProgress,
+    /// When the task was done, in seconds since the Unix epoch.
+    pub finished_at: u64,
+    /// Why the task failed, none if it succeeded.
+    #[serde(default)]
+    pub error: Option<String>,
+}
+
+impl TaskReport {
+    pub fn new(task: Task, progress: TaskProgress) -> TaskReport {
+        TaskReport { task, progress, finished_at: unix_timestamp(), error: None }
+    }
+
+    pub fn failed(task: Task, progress: TaskProgress, error: String) -> TaskReport {
+        TaskReport { error: Some(error), ..TaskReport::new(task, progress) }
+    }
+}
+
+pub fn unix_timestamp() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
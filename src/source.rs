@@ -0,0 +1,173 @@
+//! Where the crawl files are fetched from: Common Crawl by default,
+//! a mirror served over HTTP or a local copy with a `file://` URL.
+
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::str::FromStr;
+
+use url::Url;
+
+/// The base URL of the crawl files, the paths of the crawl files are relative to it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DataSource {
+    base: Url,
+}
+
+impl DataSource {
+    pub const COMMON_CRAWL: &'static str = "https://data.commoncrawl.org/";
+
+    pub fn new(mut base: Url) -> DataSource {
+        // Without a trailing slash the last segment would be replaced when joining
+        if !base.path().ends_with('/') {
+            base.set_path(&format!("{}/", base.path()));
+        }
+        DataSource { base }
+    }
+
+    /// The URL of a crawl file, e.g. `crawl-data/CC-MAIN-2023-23/warc.paths.gz`.
+    pub fn url(&self, path: &str) -> Result<Url, url::ParseError> {
+        self.base.join(path.trim_start_matches('/'))
+    }
+
+    /// The URL of the list of the WARC (or WET) files of a crawl.
+    pub fn crawl_paths_url(&self, crawl_id: &str, wet: bool) -> Result<Url, url::ParseError> {
+        let paths = if wet { "wet.paths.gz" } else { "warc.paths.gz" };
+        self.url(&format!("crawl-data/{crawl_id}/{paths}"))
+    }
+}
+
+impl Default for DataSource {
+    fn default() -> DataSource {
+        DataSource::new(Url::parse(DataSource::COMMON_CRAWL).unwrap())
+    }
+}
+
+impl FromStr for DataSource {
+    type Err = url::ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Url::parse(s).map(DataSource::new)
+    }
+}
+
+impl fmt::Display for DataSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.base)
+    }
+}
+
+/// Returns the content at the URL and its length, remote contents are
+/// downloaded into a temporary file while local ones are opened directly.
+/// The number of bytes is given to `downloaded` as they are downloaded.
+pub fn download(url: &Url, mut downloaded: impl FnMut(u64)) -> io::Result<(u64, File)> {
+    if url.scheme() == "file" {
+        let file = File::open(file_path(url)?)?;
+        let length = file.metadata()?.len();
+        downloaded(length);
+        return Ok((length, file));
+    }
+
+    let response = ureq::get(url.as_str()).call().map_err(io::Error::other)?;
+    let mut reader = CountingReader { inner: response.into_reader(), counter: downloaded };
+    let mut file = tempfile::tempfile()?;
+    let length = io::copy(&mut reader, &mut file)?;
+    file.seek(SeekFrom::Start(0))?;
+    Ok((length, file))
+}
+
+/// Gives the number of bytes read to the counter after every read.
+struct CountingReader<R, F> {
+    inner: R,
+    counter: F,
+}
+
+impl<R: Read, F: FnMut(u64)> Read for CountingReader<R, F> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        (self.counter)(read as u64);
+        Ok(read)
+    }
+}
+
+/// Reads `length` bytes at `offset` of the content at the URL,
+/// with a range request when the content is remote.
+pub fn read_range(url: &Url, offset: u64, length: u64) -> io::Result<Vec<u8>> {
+    let mut bytes = Vec::with_capacity(length as usize);
+    if url.scheme() == "file" {
+        let mut file = File::open(file_path(url)?)?;
+        file.seek(SeekFrom::Start(offset))?;
+        file.take(length).read_to_end(&mut bytes)?;
+    } else {
+        let range = format!("bytes={offset}-{}", (offset + length).saturating_sub(1));
+        let response = ureq::get(url.as_str()).set("Range", &range).call();
+        let response = response.map_err(io::Error::other)?;
+        // A server ignoring the range would send us the whole file
+        if response.status() != 206 {
+            let message = format!("{url} doesn't support range requests");
+            return Err(io::Error::new(io::ErrorKind::Unsupported, message));
+        }
+        response.into_reader().take(length).read_to_end(&mut bytes)?;
+    }
+
+    if bytes.len() as u64 != length {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(bytes)
+}
+
+fn file_path(url: &Url) -> io::Result<std::path::PathBuf> {
+    url.to_file_path().map_err(|()| {
+        io::Error::new(io::ErrorKind::InvalidInput, format!("{url} is not a valid file path"))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn crawl_files_urls() {
+        let source = DataSource::default();
+        let url = source.crawl_paths_url("CC-MAIN-2023-23", false).unwrap();
+        assert_eq!(
+            url.as_str(),
+            "https://data.commoncrawl.org/crawl-data/CC-MAIN-2023-23/warc.paths.gz"
+        );
+
+        let mirror: DataSource = "http://localhost:8080/commoncrawl".parse().unwrap();
+        let url = mirror.url("/crawl-data/CC-MAIN-2023-23/segments/1/wet/a.warc.wet.gz").unwrap();
+        assert_eq!(url.as_str(), "http://localhost:8080/commoncrawl/crawl-data/CC-MAIN-2023-23/segments/1/wet/a.warc.wet.gz");
+    }
+
+    #[test]
+    fn local_files() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("a.warc"), b"hello croissant").unwrap();
+        let source = DataSource::new(Url::from_directory_path(dir.path()).unwrap());
+        let url = source.url("a.warc").unwrap();
+
+        let mut downloaded = 0;
+        let (length, mut file) = download(&url, |bytes| downloaded += bytes).unwrap();
+        let mut content = String::new();
+        file.read_to_string(&mut content).unwrap();
+        assert_eq!((length, content.as_str()), (15, "hello croissant"));
+        assert_eq!(downloaded, 15);
+
+        assert_eq!(read_range(&url, 6, 9).unwrap(), b"croissant");
+        assert!(read_range(&url, 6, 10).is_err());
+        assert!(download(&source.url("missing.warc").unwrap(), |_| ()).is_err());
+    }
+
+    #[test]
+    fn counting_reader() {
+        let mut reads = Vec::new();
+        let mut reader =
+            CountingReader { inner: &b"hello croissant"[..], counter: |n| reads.push(n) };
+        let mut buffer = [0; 10];
+        assert_eq!(reader.read(&mut buffer).unwrap(), 10);
+        assert_eq!(reader.read(&mut buffer).unwrap(), 5);
+        assert_eq!(reader.read(&mut buffer).unwrap(), 0);
+        assert_eq!(reads, [10, 5, 0]);
+    }
+}
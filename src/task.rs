@@ -1,17 +1,441 @@
+use std::fmt;
+use std::ops::Range;
+use std::path::PathBuf;
+
+use rand::rngs::StdRng;
+use rand::seq::IteratorRandom;
+use rand::SeedableRng;
 use serde::{Deserialize, Serialize};
 use url::Url;
 
+/// A task of the queue, the tasks are processed by decreasing
+/// priority and then in the order they were enqueued.
 #[derive(Deserialize, Serialize)]
-pub enum Task {
-    WarcUrlPaths(Url),
-    WarcUrl(Url),
+pub struct Task {
+    pub priority: Priority,
+    pub kind: TaskKind,
+    /// The crawl slice the file of this task was selected for, see [`WarcPaths::slice`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub crawl_slice: Option<String>,
 }
 
 impl Task {
-    pub fn url(&self) -> &Url {
+    pub fn new(priority: Priority, kind: TaskKind) -> Task {
+        Task { priority, kind, crawl_slice: None }
+    }
+}
+
+impl fmt::Display for Task {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.kind.fmt(f)
+    }
+}
+
+#[derive(
+    Debug,
+    Default,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Deserialize,
+    Serialize,
+    clap::ValueEnum,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum Priority {
+    Low,
+    #[default]
+    Normal,
+    High,
+}
+
+impl fmt::Display for Priority {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Priority::Low => f.write_str("low"),
+            Priority::Normal => f.write_str("normal"),
+            Priority::High => f.write_str("high"),
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+pub enum TaskKind {
+    WarcUrlPaths(WarcPaths),
+    WarcUrl(Url),
+    WetUrl(Url),
+    LocalWarc(PathBuf),
+    /// Indexes the records of the URLs matching a pattern in a CDX index.
+    CdxQuery(CdxQuery),
+    /// Removes these documents from every index.
+    DeleteDocuments(Vec<u64>),
+}
+
+impl TaskKind {
+    /// The remote URL this task fetches, if any.
+    pub fn url(&self) -> Option<&Url> {
+        match self {
+            Self::WarcUrlPaths(paths) => Some(&paths.url),
+            Self::WarcUrl(url) => Some(url),
+            Self::WetUrl(url) => Some(url),
+            Self::LocalWarc(_) => None,
+            Self::CdxQuery(_) => None,
+            Self::DeleteDocuments(_) => None,
+        }
+    }
+
+    /// Whether this task extracts and indexes the documents of a WARC or WET file.
+    pub fn is_indexing(&self) -> bool {
+        matches!(self, Self::WarcUrl(_) | Self::WetUrl(_) | Self::LocalWarc(_) | Self::CdxQuery(_))
+    }
+}
+
+impl fmt::Display for TaskKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::WarcUrlPaths(paths) => write!(f, "{paths}"),
+            Self::WarcUrl(url) => write!(f, "{url}"),
+            Self::WetUrl(url) => write!(f, "{url}"),
+            Self::LocalWarc(path) => write!(f, "{}", path.display()),
+            Self::CdxQuery(query) => write!(f, "{query}"),
+            Self::DeleteDocuments(docids) => write!(f, "Delete {} documents", docids.len()),
+        }
+    }
+}
+
+/// The list of the WARC files of a crawl and the ones we want to index.
+#[derive(Deserialize, Serialize)]
+#[serde(from = "WarcPathsRepr")]
+pub struct WarcPaths {
+    /// The URL of the Gzipped list of paths, e.g. `warc.paths.gz`.
+    pub url: Url,
+    pub selection: PathsSelection,
+}
+
+impl WarcPaths {
+    pub fn new(url: Url, selection: PathsSelection) -> WarcPaths {
+        WarcPaths { url, selection }
+    }
+
+    /// The path of the list relative to the data source, whatever the data source
+    /// is, e.g. `crawl-data/CC-MAIN-2023-23/warc.paths.gz`.
+    pub fn crawl_file(&self) -> Option<&str> {
+        let path = self.url.path();
+        path.find("crawl-data/").map(|i| &path[i..])
+    }
+
+    /// Identifies the selected files of a crawl whatever the data source is, e.g.
+    /// `crawl-data/CC-MAIN-2023-23/warc.paths.gz (paths 0 to 10)`, the crawl file
+    /// alone when everything is selected.
+    pub fn slice(&self) -> Option<String> {
+        let crawl_file = self.crawl_file()?;
+        if self.selection.is_everything() {
+            Some(crawl_file.to_owned())
+        } else {
+            Some(format!("{crawl_file} ({})", self.selection))
+        }
+    }
+}
+
+/// Whether this is the id of a crawl: `CC-MAIN-{year}-{week}`, e.g. `CC-MAIN-2023-23`.
+pub fn is_crawl_id(id: &str) -> bool {
+    let Some((year, week)) = id.strip_prefix("CC-MAIN-").and_then(|d| d.split_once('-')) else {
+        return false;
+    };
+    let digits = |s: &str, len| s.len() == len && s.bytes().all(|b| b.is_ascii_digit());
+    digits(year, 4) && digits(week, 2) && (1..=53).contains(&week.parse::<u8>().unwrap_or(0))
+}
+
+/// The tasks enqueued before the selection existed only contain the URL.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum WarcPathsRepr {
+    Url(Url),
+    Full {
+        url: Url,
+        #[serde(default)]
+        selection: PathsSelection,
+    },
+}
+
+impl From<WarcPathsRepr> for WarcPaths {
+    fn from(repr: WarcPathsRepr) -> Self {
+        match repr {
+            WarcPathsRepr::Url(url) => WarcPaths::new(url, PathsSelection::default()),
+            WarcPathsRepr::Full { url, selection } => WarcPaths::new(url, selection),
+        }
+    }
+}
+
+impl fmt::Display for WarcPaths {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.selection.is_everything() {
+            write!(f, "{}", self.url)
+        } else {
+            write!(f, "{} ({})", self.url, self.selection)
+        }
+    }
+}
+
+/// The part of the WARC files of a crawl to index, everything by default.
+/// The range is applied first, then the segments and finally the sample.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct PathsSelection {
+    /// The indices of the paths to keep, in the order of the paths file.
+    pub range: Option<Range<usize>>,
+    /// Only keeps the paths in one of these segments, all of them if empty.
+    pub segments: Vec<String>,
+    /// Only keeps a random sample of the paths.
+    pub sample: Option<Sample>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Sample {
+    pub size: usize,
+    /// Makes the sample reproducible.
+    pub seed: u64,
+}
+
+impl PathsSelection {
+    pub fn is_everything(&self) -> bool {
+        *self == PathsSelection::default()
+    }
+
+    /// Returns the selected paths in the order they appear in the paths file.
+    pub fn select<'a>(&self, paths: &'a [String]) -> Vec<&'a str> {
+        let range = self.range.clone().unwrap_or(0..paths.len());
+        let paths = paths.iter().enumerate().filter(|(i, _)| range.contains(i));
+        let in_segments = |path: &str| self.segments.iter().any(|s| s == segment(path));
+        let mut paths: Vec<_> =
+            paths.filter(|(_, path)| self.segments.is_empty() || in_segments(path)).collect();
+
+        if let Some(Sample { size, seed }) = self.sample {
+            let mut rng = StdRng::seed_from_u64(seed);
+            paths = paths.into_iter().choose_multiple(&mut rng, size);
+            paths.sort_unstable_by_key(|(i, _)| *i);
+        }
+
+        paths.into_iter().map(|(_, path)| path.as_str()).collect()
+    }
+}
+
+impl fmt::Display for PathsSelection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut parts = Vec::new();
+        if let Some(Range { start, end }) = self.range {
+            parts.push(format!("paths {start} to {end}"));
+        }
+        if !self.segments.is_empty() {
+            parts.push(format!("segments {}", self.segments.join(", ")));
+        }
+        if let Some(Sample { size, seed }) = self.sample {
+            parts.push(format!("{size} sampled with seed {seed}"));
+        }
+        f.write_str(&parts.join(", "))
+    }
+}
+
+/// The segment of a Common Crawl path, e.g. `1685224643388.45` in
+/// `crawl-data/CC-MAIN-2023-23/segments/1685224643388.45/warc/CC-MAIN-(...).warc.gz`.
+fn segment(path: &str) -> &str {
+    let mut components = path.split('/').skip_while(|c| *c != "segments");
+    components.nth(1).unwrap_or_default()
+}
+
+/// Looks up the URLs matching a pattern in a CDX index and
+/// fetches only their records from the WARC files.
+#[derive(Deserialize, Serialize)]
+pub struct CdxQuery {
+    /// A pattern like `*.gouv.fr`, see [`UrlPattern`](crate::cdx::UrlPattern).
+    pub pattern: String,
+    pub source: CdxSource,
+    /// The maximum number of records to fetch, the default one if none.
+    pub limit: Option<usize>,
+}
+
+impl CdxQuery {
+    /// The maximum number of records fetched when none is given, a
+    /// pattern like `*.fr` matches hundreds of millions of them.
+    pub const DEFAULT_LIMIT: usize = 10_000;
+}
+
+impl fmt::Display for CdxQuery {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Records of {} in {}", self.pattern, self.source)?;
+        match self.limit {
+            Some(limit) => write!(f, " (at most {limit})"),
+            None => Ok(()),
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+pub enum CdxSource {
+    /// A CDX server collection, e.g. `https://index.commoncrawl.org/CC-MAIN-2023-23-index`.
+    Server(Url),
+    /// A local CDX file, Gzipped or not.
+    File(PathBuf),
+}
+
+impl fmt::Display for CdxSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            Self::WarcUrlPaths(url) => url,
-            Self::WarcUrl(url) => url,
+            CdxSource::Server(url) => write!(f, "{url}"),
+            CdxSource::File(path) => write!(f, "{}", path.display()),
+        }
+    }
+}
+
+/// The key of a task in the queue, LMDB keeps the keys sorted
+/// therefore the first key is the next task to process.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TaskKey {
+    pub priority: Priority,
+    /// A monotonically increasing id, never given to another task.
+    pub id: u64,
+    /// Whether the task was moved to the front of the queue, these
+    /// tasks have the high priority and come before all the others.
+    pub front: bool,
+}
+
+impl TaskKey {
+    pub const SIZE: usize = 1 + 8;
+
+    /// The byte of the tasks moved to the front, it is lower than the ones of the priorities.
+    const FRONT: u8 = 0;
+
+    pub fn new(priority: Priority, id: u64) -> TaskKey {
+        TaskKey { priority, id, front: false }
+    }
+
+    /// The key of a task moved to the front of the queue.
+    pub fn front(id: u64) -> TaskKey {
+        TaskKey { priority: Priority::High, id, front: true }
+    }
+
+    /// The tasks moved to the front come first, the last moved first, then the
+    /// higher priorities come first and the oldest tasks come first.
+    pub fn to_bytes(self) -> [u8; Self::SIZE] {
+        let mut bytes = [0; Self::SIZE];
+        if self.front {
+            bytes[0] = Self::FRONT;
+            bytes[1..].copy_from_slice(&(u64::MAX - self.id).to_be_bytes());
+        } else {
+            bytes[0] = u8::MAX - self.priority as u8;
+            bytes[1..].copy_from_slice(&self.id.to_be_bytes());
+        }
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Option<TaskKey> {
+        let (&priority, id) = bytes.split_first()?;
+        let id = u64::from_be_bytes(id.try_into().ok()?);
+        let priority = match priority {
+            Self::FRONT => return Some(TaskKey::front(u64::MAX - id)),
+            _ => match u8::MAX - priority {
+                0 => Priority::Low,
+                1 => Priority::Normal,
+                2 => Priority::High,
+                _ => return None,
+            },
+        };
+        Some(TaskKey::new(priority, id))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn legacy_warc_paths() {
+        let url = "https://data.commoncrawl.org/crawl-data/CC-MAIN-2023-23/warc.paths.gz";
+        let json = format!(r#"{{"WarcUrlPaths":"{url}"}}"#);
+        let kind: TaskKind = serde_json::from_str(&json).unwrap();
+        assert!(matches!(kind, TaskKind::WarcUrlPaths(p) if p.selection.is_everything()));
+
+        let selection = PathsSelection { range: Some(0..10), ..Default::default() };
+        let kind = TaskKind::WarcUrlPaths(WarcPaths::new(Url::parse(url).unwrap(), selection));
+        let json = serde_json::to_string(&kind).unwrap();
+        let kind: TaskKind = serde_json::from_str(&json).unwrap();
+        assert!(matches!(kind, TaskKind::WarcUrlPaths(p) if p.selection.range == Some(0..10)));
+    }
+
+    #[test]
+    fn crawl_ids_and_files() {
+        assert!(is_crawl_id("CC-MAIN-2023-23"));
+        assert!(!is_crawl_id("CC-MAIN-2023-54"));
+        assert!(!is_crawl_id("CC-MAIN-2023-00"));
+        assert!(!is_crawl_id("CC-MAIN-23-23"));
+        assert!(!is_crawl_id("CC-MAIN-2023-23/../.."));
+        assert!(!is_crawl_id("cc-main-2023-23"));
+
+        let url = "http://localhost:8080/cc/crawl-data/CC-MAIN-2023-23/wet.paths.gz";
+        let paths = WarcPaths::new(Url::parse(url).unwrap(), PathsSelection::default());
+        assert_eq!(paths.crawl_file(), Some("crawl-data/CC-MAIN-2023-23/wet.paths.gz"));
+        assert_eq!(paths.slice().as_deref(), paths.crawl_file());
+
+        let selection = PathsSelection { range: Some(0..10), ..Default::default() };
+        let paths = WarcPaths::new(paths.url, selection);
+        let slice = "crawl-data/CC-MAIN-2023-23/wet.paths.gz (paths 0 to 10)";
+        assert_eq!(paths.slice().as_deref(), Some(slice));
+    }
+
+    #[test]
+    fn select_paths() {
+        let paths: Vec<_> = (0..100)
+            .map(|i| format!("crawl-data/CC/segments/{}/warc/{i}.warc.gz", i % 4))
+            .collect();
+
+        let everything = PathsSelection::default();
+        assert_eq!(everything.select(&paths).len(), 100);
+
+        let range = PathsSelection { range: Some(10..20), ..Default::default() };
+        assert_eq!(
+            range.select(&paths),
+            paths[10..20].iter().map(String::as_str).collect::<Vec<_>>()
+        );
+
+        let segments = vec![String::from("1"), String::from("3")];
+        let selection = PathsSelection { range: Some(0..10), segments, sample: None };
+        let selected = selection.select(&paths);
+        assert_eq!(selected.len(), 5);
+        assert!(selected[0].ends_with("/1.warc.gz") && selected[1].ends_with("/3.warc.gz"));
+
+        let sample = Some(Sample { size: 10, seed: 42 });
+        let sampled = PathsSelection { sample, ..Default::default() };
+        let selected = sampled.select(&paths);
+        assert_eq!(selected.len(), 10);
+        assert_eq!(selected, sampled.select(&paths));
+        assert!(selected.windows(2).all(|w| {
+            let index = |p: &str| paths.iter().position(|q| q == p).unwrap();
+            index(w[0]) < index(w[1])
+        }));
+    }
+
+    #[test]
+    fn task_keys_order() {
+        let keys = [
+            TaskKey::new(Priority::Low, 0),
+            TaskKey::new(Priority::Normal, 3),
+            TaskKey::front(5),
+            TaskKey::new(Priority::High, 2),
+            TaskKey::new(Priority::Normal, 1),
+            TaskKey::front(4),
+            TaskKey::new(Priority::High, 256),
+        ];
+
+        let mut sorted = keys;
+        sorted.sort_by_key(|key| key.to_bytes());
+        let ids: Vec<_> = sorted.iter().map(|key| key.id).collect();
+        assert_eq!(ids, [5, 4, 2, 256, 1, 3, 0]);
+
+        for key in keys {
+            assert_eq!(TaskKey::from_bytes(&key.to_bytes()), Some(key));
         }
+        assert_eq!(TaskKey::from_bytes(&[0; 3]), None);
     }
 }
@@ -0,0 +1,23 @@
+use std::borrow::Cow;
+
+use heed::BoxedError;
+
+use crate::task::TaskKey;
+
+pub struct TaskKeyCodec;
+
+impl heed::BytesDecode<'_> for TaskKeyCodec {
+    type DItem = TaskKey;
+
+    fn bytes_decode(bytes: &[u8]) -> Result<Self::DItem, BoxedError> {
+        TaskKey::from_bytes(bytes).ok_or_else(|| "invalid task key".into())
+    }
+}
+
+impl heed::BytesEncode<'_> for TaskKeyCodec {
+    type EItem = TaskKey;
+
+    fn bytes_encode(item: &Self::EItem) -> Result<Cow<[u8]>, BoxedError> {
+        Ok(Cow::Owned(item.to_bytes().to_vec()))
+    }
+}
@@ -2,6 +2,7 @@ use self::diacritics::RemoveDiacritics;
 use self::whitespace::ShrinkWhitespaces;
 
 pub mod diacritics;
+pub mod simhash;
 pub mod trigrams;
 pub mod whitespace;
 
@@ -0,0 +1,114 @@
+/// Computes the SimHash fingerprint of a document from its trigrams,
+/// near-duplicate documents have fingerprints with a small Hamming distance.
+///
+/// <https://en.wikipedia.org/wiki/SimHash>
+#[derive(Debug, Clone)]
+pub struct SimHasher {
+    weights: [i64; 64],
+    count: u64,
+}
+
+impl Default for SimHasher {
+    fn default() -> Self {
+        SimHasher { weights: [0; 64], count: 0 }
+    }
+}
+
+impl SimHasher {
+    pub fn new() -> Self {
+        SimHasher::default()
+    }
+
+    pub fn add(&mut self, trigram: [char; 3]) {
+        let hash = fnv1a(trigram);
+        for (i, weight) in self.weights.iter_mut().enumerate() {
+            if hash & (1 << i) != 0 {
+                *weight += 1;
+            } else {
+                *weight -= 1;
+            }
+        }
+        self.count += 1;
+    }
+
+    /// Returns the fingerprint or `None` if no trigram was added.
+    pub fn finish(&self) -> Option<u64> {
+        if self.count == 0 {
+            return None;
+        }
+
+        let mut fingerprint = 0;
+        for (i, weight) in self.weights.iter().enumerate() {
+            if *weight > 0 {
+                fingerprint |= 1 << i;
+            }
+        }
+        Some(fingerprint)
+    }
+}
+
+/// The maximum number of differing bits between the fingerprints
+/// of two documents for them to be considered near-duplicates.
+pub const NEAR_DUPLICATE_DISTANCE: u32 = 3;
+
+/// The number of bits that differ between two fingerprints.
+pub fn hamming_distance(a: u64, b: u64) -> u32 {
+    (a ^ b).count_ones()
+}
+
+/// The 64 bits FNV-1a hash of a trigram, fingerprints are stored
+/// so we need a hash function that is stable across Rust versions.
+fn fnv1a(trigram: [char; 3]) -> u64 {
+    const OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+    const PRIME: u64 = 0x100000001b3;
+
+    let mut hash = OFFSET_BASIS;
+    let mut buffer = [0; 4];
+    for c in trigram {
+        for byte in c.encode_utf8(&mut buffer).bytes() {
+            hash ^= u64::from(byte);
+            hash = hash.wrapping_mul(PRIME);
+        }
+    }
+    hash
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::text::cleanup_chars;
+    use crate::text::trigrams::TriGrams;
+
+    fn simhash(text: &str) -> Option<u64> {
+        let mut hasher = SimHasher::new();
+        TriGrams::new(cleanup_chars(text.chars())).for_each(|t| hasher.add(t));
+        hasher.finish()
+    }
+
+    #[test]
+    fn near_duplicates() {
+        let original = simhash(
+            "Le croissant est une viennoiserie à base de pâte levée feuilletée, \
+             spécifique de la pâtisserie française, faite de farine, d'eau, de beurre et de sel.",
+        )
+        .unwrap();
+        let mirror = simhash(
+            "Le croissant est une viennoiserie à base de pâte levée feuilletée, \
+             spécifique de la pâtisserie française, faite de farine, d'eau, de beurre et de sucre.",
+        )
+        .unwrap();
+        let different = simhash(
+            "The Common Crawl corpus contains petabytes of data, regularly collected \
+             since 2008, it contains raw web page data, metadata extracts and text extracts.",
+        )
+        .unwrap();
+
+        assert!(hamming_distance(original, mirror) <= NEAR_DUPLICATE_DISTANCE);
+        assert!(hamming_distance(original, different) > 16);
+    }
+
+    #[test]
+    fn empty() {
+        assert_eq!(SimHasher::new().finish(), None);
+    }
+}
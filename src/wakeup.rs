@@ -0,0 +1,62 @@
+//! The indexer listens on a Unix socket next to the database so that
+//! the search server can wake it up as soon as it enqueues a task.
+
+use std::io;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::Sender;
+use std::thread;
+
+/// The path of the socket the indexer listens on.
+pub fn socket_path(database_path: &Path) -> PathBuf {
+    database_path.join("indexer.sock")
+}
+
+/// Tells the indexer that new tasks were enqueued, does nothing if
+/// the indexer is not running, it will see the tasks when it starts.
+pub fn notify_indexer(database_path: &Path) {
+    let _ = UnixStream::connect(socket_path(database_path));
+}
+
+/// Listens for wakeups in a background thread and forwards them into the channel.
+pub fn listen_wakeups(database_path: &Path, sender: Sender<()>) -> io::Result<()> {
+    let path = socket_path(database_path);
+    // The socket of a previous indexer that didn't stop gracefully is still there
+    match std::fs::remove_file(&path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+        _ => (),
+    }
+
+    let listener = UnixListener::bind(path)?;
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            if stream.is_ok() && sender.send(()).is_err() {
+                break;
+            }
+        }
+    });
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc;
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn wakeup_the_indexer() {
+        let dir = tempfile::tempdir().unwrap();
+        // Nobody listens yet
+        notify_indexer(dir.path());
+
+        let (sender, receiver) = mpsc::channel();
+        listen_wakeups(dir.path(), sender).unwrap();
+        assert!(receiver.try_recv().is_err());
+
+        notify_indexer(dir.path());
+        assert!(receiver.recv_timeout(Duration::from_secs(5)).is_ok());
+    }
+}
@@ -0,0 +1,181 @@
+//! Parsing of the Common Crawl WAT files, the JSON metadata extracted
+//! from the HTML pages of the paired WARC files.
+//!
+//! <https://commoncrawl.org/blog/navigating-the-warc-file-format>
+
+use std::collections::HashSet;
+
+use serde::Deserialize;
+use url::Url;
+
+/// The metadata of a single WARC response record found in a WAT file.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct WatMetadata {
+    pub title: Option<String>,
+    pub lang: Option<String>,
+    pub description: Option<String>,
+    /// The absolute http(s) links found in the page, deduplicated.
+    pub outlinks: Vec<Url>,
+}
+
+impl WatMetadata {
+    /// Parses the JSON payload of a WAT metadata record, the base URL
+    /// is used to resolve the relative links of the page.
+    pub fn from_json(json: &[u8], base: &Url) -> serde_json::Result<WatMetadata> {
+        let WatRecord { envelope } = serde_json::from_slice(json)?;
+        let Some(html) = envelope.payload_metadata.http_response_metadata.html_metadata else {
+            return Ok(WatMetadata::default());
+        };
+
+        let title = html.head.title.map(|t| t.trim().to_owned()).filter(|t| !t.is_empty());
+        let metas = &html.head.metas;
+        let lang = html.head.lang.or_else(|| {
+            metas
+                .iter()
+                .find(|m| m.is_named("language") || m.is_http_equiv("content-language"))
+                .and_then(|m| m.content.clone())
+        });
+        let description =
+            metas.iter().find(|m| m.is_named("description")).and_then(|m| m.content.clone());
+
+        // The links are kept in the order of the page
+        let mut seen = HashSet::new();
+        let mut outlinks = Vec::new();
+        for link in html.links {
+            if let Ok(url) = base.join(&link.url) {
+                if matches!(url.scheme(), "http" | "https") && seen.insert(url.clone()) {
+                    outlinks.push(url);
+                }
+            }
+        }
+
+        Ok(WatMetadata { title, lang, description, outlinks })
+    }
+}
+
+/// Returns the URL of the WAT file paired with the given Common Crawl WARC file.
+///
+/// `.../segments/{segment}/warc/{name}.warc.gz` becomes `.../segments/{segment}/wat/{name}.warc.wat.gz`.
+pub fn wat_url_for_warc(warc: &Url) -> Option<Url> {
+    let path = warc.path();
+    let (dir, name) = path.rsplit_once("/warc/")?;
+    let name = name.strip_suffix(".warc.gz")?;
+    let mut url = warc.clone();
+    url.set_path(&format!("{dir}/wat/{name}.warc.wat.gz"));
+    Some(url)
+}
+
+#[derive(Deserialize)]
+struct WatRecord {
+    #[serde(rename = "Envelope")]
+    envelope: Envelope,
+}
+
+#[derive(Deserialize)]
+struct Envelope {
+    #[serde(rename = "Payload-Metadata", default)]
+    payload_metadata: PayloadMetadata,
+}
+
+#[derive(Default, Deserialize)]
+struct PayloadMetadata {
+    #[serde(rename = "HTTP-Response-Metadata", default)]
+    http_response_metadata: HttpResponseMetadata,
+}
+
+#[derive(Default, Deserialize)]
+struct HttpResponseMetadata {
+    #[serde(rename = "HTML-Metadata")]
+    html_metadata: Option<HtmlMetadata>,
+}
+
+#[derive(Deserialize)]
+struct HtmlMetadata {
+    #[serde(rename = "Head", default)]
+    head: Head,
+    #[serde(rename = "Links", default)]
+    links: Vec<Link>,
+}
+
+#[derive(Default, Deserialize)]
+struct Head {
+    #[serde(rename = "Title")]
+    title: Option<String>,
+    /// Not always present, only recent WAT files contain the `<html lang>` attribute.
+    #[serde(rename = "Lang")]
+    lang: Option<String>,
+    #[serde(rename = "Metas", default)]
+    metas: Vec<Meta>,
+}
+
+#[derive(Deserialize)]
+struct Meta {
+    name: Option<String>,
+    #[serde(rename = "http-equiv")]
+    http_equiv: Option<String>,
+    content: Option<String>,
+}
+
+impl Meta {
+    fn is_named(&self, name: &str) -> bool {
+        self.name.as_deref().map_or(false, |n| n.eq_ignore_ascii_case(name))
+    }
+
+    fn is_http_equiv(&self, value: &str) -> bool {
+        self.http_equiv.as_deref().map_or(false, |h| h.eq_ignore_ascii_case(value))
+    }
+}
+
+#[derive(Deserialize)]
+struct Link {
+    url: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_html_metadata() {
+        let json = br#"{"Envelope":{"Payload-Metadata":{"HTTP-Response-Metadata":{
+            "HTML-Metadata":{
+                "Head":{"Title":" Le croissant ","Metas":[
+                    {"name":"description","content":"Tout sur les croissants"},
+                    {"http-equiv":"content-language","content":"fr"}
+                ]},
+                "Links":[
+                    {"path":"A@/href","url":"/recettes"},
+                    {"path":"A@/href","url":"https://example.org/"},
+                    {"path":"A@/href","url":"/recettes"},
+                    {"path":"A@/href","url":"mailto:chef@example.com"}
+                ]
+            }
+        }}}}"#;
+
+        let base = Url::parse("https://croissant.fr/index.html").unwrap();
+        let metadata = WatMetadata::from_json(json, &base).unwrap();
+        assert_eq!(metadata.title.as_deref(), Some("Le croissant"));
+        assert_eq!(metadata.lang.as_deref(), Some("fr"));
+        assert_eq!(metadata.description.as_deref(), Some("Tout sur les croissants"));
+        assert_eq!(
+            metadata.outlinks,
+            [
+                Url::parse("https://croissant.fr/recettes").unwrap(),
+                Url::parse("https://example.org/").unwrap(),
+            ]
+        );
+    }
+
+    #[test]
+    fn paired_wat_url() {
+        let warc = Url::parse(
+            "https://data.commoncrawl.org/crawl-data/CC-MAIN-2023-23/segments/1685224643388.45/warc/CC-MAIN-20230527223515-20230528013515-00000.warc.gz",
+        )
+        .unwrap();
+        let wat = wat_url_for_warc(&warc).unwrap();
+        assert_eq!(
+            wat.as_str(),
+            "https://data.commoncrawl.org/crawl-data/CC-MAIN-2023-23/segments/1685224643388.45/wat/CC-MAIN-20230527223515-20230528013515-00000.warc.wat.gz",
+        );
+    }
+}